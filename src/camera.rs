@@ -0,0 +1,81 @@
+use crate::game::{EYE_HEIGHT, FOV};
+use crate::ray::Ray;
+
+/// A viewpoint looking along `direction`, with a projection plane in front of
+/// it that the screen is mapped onto. Columns evenly spaced across the screen
+/// are evenly spaced across the plane, and the plane's width sets the field of view.
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    pub pos: [f32; 2],
+    /// Unit vector the camera is looking along.
+    pub direction: [f32; 2],
+    /// From the centre of the projection plane to its right edge, at unit
+    /// distance in front of the camera.
+    pub plane: [f32; 2],
+    /// Height of the horizon on the screen, from -1.0 at the bottom to 1.0 at
+    /// the top. Looking up or down shears everything by moving it.
+    pub horizon: f32,
+    /// Height of the camera above the floor, in standard wall heights.
+    pub eye: f32,
+}
+
+impl Camera {
+    /// A camera at `pos` facing `rotation` degrees, seeing `fov` degrees across.
+    pub fn new(pos: [f32; 2], rotation: f32, fov: f32) -> Self {
+        let rad = rotation.to_radians();
+        let half_width = (fov / 2.0).to_radians().tan();
+        Self {
+            pos,
+            direction: [-rad.sin(), rad.cos()],
+            plane: [rad.cos() * half_width, rad.sin() * half_width],
+            horizon: 0.0,
+            eye: EYE_HEIGHT,
+        }
+    }
+
+    fn half_width(&self) -> f32 {
+        self.plane[0].hypot(self.plane[1])
+    }
+
+    /// The ray through a point on the screen, from -1.0 at the left edge to
+    /// 1.0 at the right.
+    pub fn ray(&self, screen_x: f32) -> Ray {
+        let x = self.direction[0] + self.plane[0] * screen_x;
+        let y = self.direction[1] + self.plane[1] * screen_x;
+        Ray {
+            origin: self.pos,
+            length: 0.0,
+            rotation: (-x).atan2(y).to_degrees().rem_euclid(360.0),
+        }
+    }
+
+    /// Converts a distance along the ray through `screen_x` into a distance
+    /// along the camera's direction, so walls don't bulge at the centre.
+    pub fn depth(&self, screen_x: f32, distance: f32) -> f32 {
+        distance / (1.0 + (screen_x * self.half_width()).powi(2)).sqrt()
+    }
+
+    /// Where a point lands across the screen and how far in front of the
+    /// camera it is, or None if it is behind the camera.
+    pub fn project(&self, point: [f32; 2]) -> Option<(f32, f32)> {
+        let rel = [point[0] - self.pos[0], point[1] - self.pos[1]];
+        let depth = self.direction[0] * rel[0] + self.direction[1] * rel[1];
+        if depth <= 0.0 {
+            return None;
+        }
+        let across = (self.plane[0] * rel[0] + self.plane[1] * rel[1]) / self.half_width();
+        Some((self.screen_width(across, depth), depth))
+    }
+
+    /// How much of the screen's half width a length across the view takes up
+    /// at `depth`.
+    pub fn screen_width(&self, length: f32, depth: f32) -> f32 {
+        length / (depth * self.half_width())
+    }
+
+    /// How much bigger things appear than at the default field of view.
+    /// Heights are scaled by this so that the view keeps its proportions.
+    pub fn zoom(&self) -> f32 {
+        (FOV / 2.0).to_radians().tan() / self.half_width()
+    }
+}
@@ -0,0 +1,58 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FogMode {
+    Off,
+    /// Fog thickens evenly until everything `1.0 / density` away is hidden.
+    Linear,
+    /// Fog thickens quickly up close and tails off with distance.
+    Exponential,
+}
+
+/// Fog blending the 3D view towards a colour with distance from the camera.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub color: [f32; 3],
+    /// How quickly the fog thickens, per world unit.
+    pub density: f32,
+    pub mode: FogMode,
+}
+
+impl Fog {
+    pub fn none() -> Self {
+        Self {
+            color: [0.0, 0.0, 0.0],
+            density: 0.0,
+            mode: FogMode::Off,
+        }
+    }
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// Fog laid out for the shader's uniform buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogUniform {
+    color: [f32; 3],
+    density: f32,
+    mode: u32,
+    _padding: [u32; 3],
+}
+
+impl From<Fog> for FogUniform {
+    fn from(fog: Fog) -> Self {
+        Self {
+            color: fog.color,
+            density: fog.density,
+            mode: match fog.mode {
+                FogMode::Off => 0,
+                FogMode::Linear => 1,
+                FogMode::Exponential => 2,
+            },
+            _padding: [0; 3],
+        }
+    }
+}
@@ -1,11 +1,23 @@
+use std::collections::HashSet;
+
 use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
 
-use crate::ray::Ray;
+use crate::camera::Camera;
+use crate::fog::{Fog, FogMode};
+use crate::gpu_raycast::{RaycastFrame, RaycastLights};
+use crate::ray::{map_rays, Leg, Ray};
 use crate::util::convert_range;
 use crate::{
-    graphics::{Graphics, Rect},
+    graphics::{Graphics, Line, Quad, Rect},
+    light::{light_at, Light, LightGrid},
+    map::{
+        DoorState, GameMap, Lift, Portal, Segment, DOOR_TILE, FENCE_TILE, GRATE_TILE, MIRROR_TILE,
+        PUSH_WALL_TILE, WINDOW_TILE,
+    },
     player::{Player, LINE_LENGTH},
-    ray::Lighting,
+    ray::{CellSpan, Face, RayHit},
+    sprite::Sprite,
+    texture::{atlas_uv, face_shades, tile_slot, BARREL_SLOT, PILLAR_SLOT, PLANT_SLOT},
 };
 
 pub const MOVE_AMOUNT: f32 = 0.01;
@@ -13,23 +25,142 @@ pub const ROTATE_AMOUNT: f32 = 10.0;
 pub const MAP_SIZE: usize = 10;
 pub const COORD_SIZE: usize = 2;
 pub const WALL_COLOR: [f32; 3] = [255.0, 255.0, 255.0];
-pub const CELL_WIDTH: f32 = COORD_SIZE as f32 / MAP_SIZE as f32;
 pub const PLAYER_COLOR: [f32; 3] = [0.0, 255.0, 0.0];
 pub const GROUND_COLOR: [f32; 3] = [0.1, 0.30, 0.0];
 pub const SKY_COLOR: [f32; 3] = [0.2, 0.4, 1.0];
 pub const PLAYER_WIDTH: f32 = 0.03;
-pub const SHADED_COLOR: [f32; 3] = [0.5, 0.0, 0.0];
-pub const LIT_COLOR: [f32; 3] = [1.0, 0.0, 0.0];
-pub type GameMap = [[u8; MAP_SIZE]; MAP_SIZE];
+/// Colour of the rays drawn on the map, shaded like the face each one hits.
+pub const RAY_COLOR: [f32; 3] = [1.0, 0.0, 0.0];
+pub const LIT_TINT: [f32; 3] = [1.0, 1.0, 1.0];
+pub const WALL_HEIGHT: f32 = 0.5;
+/// Height of the player's eyes above the floor when standing and when
+/// crouching, in standard wall heights.
+pub const EYE_HEIGHT: f32 = 0.5;
+pub const CROUCH_EYE_HEIGHT: f32 = 0.25;
+/// Upward speed at the start of a jump and how much of it is lost each frame,
+/// in standard wall heights.
+pub const JUMP_SPEED: f32 = 0.04;
+pub const GRAVITY: f32 = 0.004;
+/// How far the horizon moves each time the player looks up or down, and how
+/// far from the centre of the screen it may go.
+pub const PITCH_STEP: f32 = 0.1;
+pub const MAX_PITCH: f32 = 0.8;
+/// The light of the default level away from its lamps.
+pub const AMBIENT_LIGHT: [f32; 3] = [0.45, 0.45, 0.5];
+/// Field of view in degrees the game starts with.
+pub const FOV: f32 = 60.0;
+pub const MIN_FOV: f32 = 30.0;
+pub const MAX_FOV: f32 = 120.0;
+pub const FOV_STEP: f32 = 5.0;
+/// Render resolutions cycled through with R, in columns across the 3D view.
+/// None casts one column for every pixel of the view.
+pub const RESOLUTIONS: [Option<usize>; 4] = [None, Some(320), Some(160), Some(80)];
+pub const SPRITE_COLOR: [f32; 3] = [1.0, 0.8, 0.0];
+pub const SPRITE_MARKER_WIDTH: f32 = 0.04;
+pub const LIFT_COLOR: [f32; 3] = [0.0, 0.8, 1.0];
+pub const PORTAL_COLOR: [f32; 3] = [1.0, 0.0, 1.0];
+/// How many mirrors a ray bounces off before a mirror is drawn as a wall.
+pub const MIRROR_BOUNCES: usize = 4;
+/// Colour everything seen in a mirror is tinted by, once per bounce.
+pub const MIRROR_TINT: [f32; 3] = [0.75, 0.85, 0.9];
+/// How far a door slides each frame, as a fraction of its width.
+pub const DOOR_SPEED: f32 = 0.02;
+/// How far a push wall slides each frame, as a fraction of a cell.
+pub const PUSH_WALL_SPEED: f32 = 0.02;
+pub const DEFAULT_FOG: Fog = Fog {
+    color: [0.05, 0.05, 0.08],
+    density: 0.5,
+    mode: FogMode::Exponential,
+};
+/// How far rays are cast before giving up, in world units.
+pub const VIEW_DISTANCE: f32 = 8.0;
+/// How many points along each side of a cell light is sampled at, both when
+/// baking lightmaps and for the floors and ceilings each frame, and so how
+/// many pieces a floor is drawn in.
+pub const LIGHT_SAMPLES: usize = 4;
+pub const LIGHT_MARKER_WIDTH: f32 = 0.03;
+pub const DEFAULT_FLOOR: u8 = 8;
+pub const DEFAULT_CEILING: u8 = 9;
 
 pub struct Game {
     pub graphics: Graphics,
     player: Player,
-    map: GameMap,
-    ray_data: Vec<(f32, Lighting)>,
+    /// The grids of a level stacked from the ground up, each a standard wall
+    /// height above the one below.
+    storeys: Vec<GameMap>,
+    /// Index into `storeys` of the storey the player is on.
+    storey: usize,
+    /// The cell the player was last in, so that lifts only move them when
+    /// they step into the lift's cell. None if they were off the map.
+    player_cell: Option<[usize; 2]>,
+    /// The columns of each storey, left to right.
+    ray_data: Vec<Vec<Column>>,
+    /// The light on the floors and ceilings of each storey.
+    light_grids: Vec<LightGrid>,
+    /// The storeys as they were when the light was last worked out, which
+    /// only has to be worked out again once they change.
+    lit_storeys: Vec<GameMap>,
+    /// Whether the GPU raycaster has yet to be given the light as it is now.
+    gpu_lights_stale: bool,
+    strips: Vec<Strip>,
+    sprites: Vec<Sprite>,
     mouse_location: [f32; 2],
     mouse_left: bool,
     mouse_right: bool,
+    /// The storeys whose walls were edited while dragging the mouse, whose
+    /// lightmaps are baked again once it is released.
+    edited_storeys: HashSet<usize>,
+    selected_tile: u8,
+    /// Horizontal field of view in degrees.
+    fov: f32,
+    /// Index into RESOLUTIONS of the render resolution in use.
+    resolution: usize,
+    /// Whether walls, floors, ceilings and sprites are raycast on the GPU
+    /// instead of being built on the CPU.
+    gpu_raycast: bool,
+}
+
+/// What the ray of one column of the 3D view ran into, following it through
+/// any portals. Distances are measured along the whole path of the ray.
+struct Column {
+    /// Hits nearest first. The last may be the hit that stopped the ray.
+    hits: Vec<ColumnHit>,
+    /// Corrected distance at which the ray was stopped or first went into a
+    /// portal, or the view distance if it did neither.
+    depth: f32,
+    /// The cells whose floor and ceiling the ray crossed, at corrected distances.
+    spans: Vec<ColumnSpan>,
+}
+
+/// A hit on a column's ray, at its fisheye corrected distance.
+struct ColumnHit {
+    depth: f32,
+    /// The storey whose map the hit is on.
+    storey: usize,
+    /// Colour the hit is tinted by for the mirrors the ray bounced off first.
+    tint: [f32; 3],
+    hit: RayHit,
+}
+
+/// A cell crossed by a column's ray, at fisheye corrected distances.
+struct ColumnSpan {
+    /// The storey whose map the cell is on.
+    storey: usize,
+    /// Colour the floor and ceiling are tinted by for the mirrors the ray
+    /// bounced off first.
+    tint: [f32; 3],
+    span: CellSpan,
+}
+
+/// A column of a floor, wall or sprite in the 3D view, waiting to be drawn in
+/// depth order.
+struct Strip {
+    depth: f32,
+    quad: Quad,
+    color: [f32; 3],
+    tex_coords: Quad,
+    /// Distance of each corner from the camera, in `tl`, `bl`, `br`, `tr` order.
+    depths: [f32; 4],
 }
 
 enum HandleWall {
@@ -41,7 +172,7 @@ impl Game {
     pub fn new(graphics: Graphics) -> Self {
         let pos = [0.0, 0.0];
         let width = PLAYER_WIDTH;
-        let ray_lengths: Vec<(f32, Lighting)> = vec![];
+        let ray_lengths: Vec<Vec<Column>> = vec![];
         let mouse_location = [0.0, 0.0];
         let mouse_left = false;
         let mouse_right = false;
@@ -55,63 +186,307 @@ impl Game {
             width,
             rotation: 0.0,
             view,
+            pitch: 0.0,
+            jump: 0.0,
+            jump_speed: 0.0,
+            crouching: false,
         };
 
-        let map: GameMap = [
-            [1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
-            [1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+        let mut map = GameMap::bordered(MAP_SIZE, MAP_SIZE);
+        map.fill_floor_and_ceiling(DEFAULT_FLOOR, DEFAULT_CEILING);
+        // A wall splitting off the south of the map, with a door through it
+        for x in 1..MAP_SIZE - 1 {
+            map[(x, 3)] = 1;
+        }
+        map[(5, 3)] = DOOR_TILE;
+        map[(3, 7)] = PUSH_WALL_TILE;
+        map[(2, 3)] = WINDOW_TILE;
+        map[(8, 3)] = GRATE_TILE;
+        map[(7, 6)] = FENCE_TILE;
+        map[(8, 6)] = FENCE_TILE;
+        // A pair of diagonal walls and a thin partition standing off the grid
+        map.add_segment(Segment::new([3.5, 8.5], [4.5, 7.5], 2));
+        map.add_segment(Segment::new([5.5, 7.5], [6.5, 8.5], 2));
+        map.add_segment(Segment::new([8.0, 4.4], [8.0, 5.6], 3));
+        // A low wall and a counter to see over, and a tower to see above the rest
+        map[(2, 5)] = 2;
+        map[(3, 5)] = 2;
+        map.set_wall_height(2, 5, 0.25);
+        map.set_wall_height(3, 5, 0.25);
+        map[(6, 5)] = 3;
+        map.set_wall_height(6, 5, 0.5);
+        map.set_wall_height(0, 8, 2.0);
+        // A pair of mirrors along the west wall
+        map[(0, 4)] = MIRROR_TILE;
+        map[(0, 5)] = MIRROR_TILE;
+        // A warm lamp in the south room, which shines out through the window,
+        // and a cold one that the diagonal walls and the push wall cast
+        // shadows from. The cold lamp is dynamic so that its shadows follow
+        // the push wall, and the rest is baked
+        map.set_ambient(AMBIENT_LIGHT);
+        map.add_light(Light::new([4.5, 1.5], [1.0, 0.7, 0.4], 4.0, 0.8));
+        map.add_light(Light {
+            dynamic: true,
+            ..Light::new([4.5, 6.5], [0.5, 0.7, 1.0], 4.0, 0.7)
+        });
+        map.bake_lightmap(LIGHT_SAMPLES);
+        map.set_fog(DEFAULT_FOG);
+
+        // A balcony over the north of the map behind a low parapet, open to
+        // the sky above the rest, with a lift up to it in the corner
+        let mut balcony = GameMap::bordered(MAP_SIZE, MAP_SIZE);
+        for x in 1..MAP_SIZE - 1 {
+            balcony[(x, 6)] = 2;
+            balcony.set_wall_height(x, 6, 0.25);
+            for y in 0..6 {
+                map.set_ceiling(x, y, 0);
+            }
+            for y in 6..MAP_SIZE {
+                balcony.set_floor(x, y, DEFAULT_FLOOR);
+            }
+        }
+        map.set_lift(8, 8, Some(Lift::Up));
+        balcony.set_lift(8, 8, Some(Lift::Down));
+
+        // The west and east walls of the south room lead into each other, and
+        // its south wall leads out of the north wall of the balcony
+        let portal = |storey, cell, face| Some(Portal { storey, cell, face });
+        map.set_portal([0, 1], Face::East, portal(0, [9, 1], Face::West));
+        map.set_portal([9, 1], Face::West, portal(0, [0, 1], Face::East));
+        map.set_portal([4, 0], Face::North, portal(1, [4, 9], Face::South));
+        balcony.set_portal([4, 9], Face::South, portal(0, [4, 0], Face::North));
+        balcony.set_ambient(AMBIENT_LIGHT);
+        balcony.add_light(Light::new([2.5, 8.5], [1.0, 0.9, 0.7], 5.0, 0.8));
+        balcony.bake_lightmap(LIGHT_SAMPLES);
+        balcony.set_fog(DEFAULT_FOG);
+
+        let sprites = vec![
+            Sprite::new(map.cell_center(2, 2), BARREL_SLOT),
+            Sprite::new(map.cell_center(7, 7), BARREL_SLOT),
+            Sprite::new(map.cell_center(2, 7), PILLAR_SLOT),
+            Sprite::new(map.cell_center(7, 2), PLANT_SLOT),
+            Sprite {
+                storey: 1,
+                ..Sprite::new(balcony.cell_center(4, 8), PLANT_SLOT)
+            },
         ];
+        let player_cell = to_cell(&map, map.to_map_space(pos));
 
-        assert!(map.len() == MAP_SIZE);
         Self {
             graphics,
             mouse_right,
             mouse_left,
             player,
-            map,
+            storeys: vec![map, balcony],
+            storey: 0,
+            player_cell,
             ray_data: ray_lengths,
+            light_grids: vec![],
+            lit_storeys: vec![],
+            gpu_lights_stale: true,
+            strips: vec![],
+            sprites,
             mouse_location,
+            edited_storeys: HashSet::new(),
+            selected_tile: 1,
+            fov: FOV,
+            resolution: 0,
+            gpu_raycast: false,
         }
     }
 
     fn draw_map(&mut self) {
-        let n = self.map.len();
-        for i in 0..n {
-            for j in 0..n {
-                if self.map[i][j] == 1 {
-                    let origin = [
-                        (i as f32 * CELL_WIDTH) + (CELL_WIDTH / 2.0) - 1.0,
-                        (j as f32 * CELL_WIDTH) + (CELL_WIDTH / 2.0) - 1.0,
-                    ];
-                    let width = CELL_WIDTH - 0.01;
+        let cell_width = self.map().cell_width();
+        for i in 0..self.map().width() {
+            for j in 0..self.map().height() {
+                let tile = self.map()[(i, j)];
+                if self.map().is_door(i, j) {
+                    self.draw_map_door(i, j);
+                } else if tile != 0 {
+                    let origin = self.map().cell_center(i, j);
+                    let width = cell_width - 0.01;
                     let color = WALL_COLOR;
                     let rotation = 0.0;
+                    let rect = Rect {
+                        origin,
+                        rotation,
+                        height: width,
+                        width,
+                    };
+                    let tex_coords = Quad::texture(tile_slot(tile), [0.0, 1.0], [0.0, 1.0]);
 
-                    self.graphics.push_square(origin, width, color, rotation)
+                    self.graphics.push_textured_rect(rect, color, tex_coords)
                 }
             }
         }
+
+        for wall in self.storeys[self.storey].push_walls() {
+            let (min, _) = wall.bounds();
+            let width = cell_width - 0.01;
+            let rect = Rect {
+                origin: [
+                    (min[0] + 0.5) * cell_width - 1.0,
+                    (min[1] + 0.5) * cell_width - 1.0,
+                ],
+                rotation: 0.0,
+                height: width,
+                width,
+            };
+            let tex_coords = Quad::texture(tile_slot(wall.tile), [0.0, 1.0], [0.0, 1.0]);
+            self.graphics
+                .push_textured_rect(rect, WALL_COLOR, tex_coords);
+        }
+
+        let to_world = |[x, y]: [f32; 2]| [x * cell_width - 1.0, y * cell_width - 1.0];
+        for segment in self.storeys[self.storey].segments() {
+            let line = Line {
+                start: to_world(segment.start),
+                end: to_world(segment.end),
+            };
+            self.graphics.push_line(line, WALL_COLOR);
+        }
+
+        for (cell, face, _) in self.storeys[self.storey].portals() {
+            let [x, y] = [cell[0] as f32 + 0.5, cell[1] as f32 + 0.5];
+            let [nx, ny] = face.normal();
+            // The face runs across the normal, half a cell out from the centre
+            let centre = [x + nx / 2.0, y + ny / 2.0];
+            let line = Line {
+                start: to_world([centre[0] - ny / 2.0, centre[1] + nx / 2.0]),
+                end: to_world([centre[0] + ny / 2.0, centre[1] - nx / 2.0]),
+            };
+            self.graphics.push_line(line, PORTAL_COLOR);
+        }
+
+        for ([x, y], _) in self.storeys[self.storey].lifts() {
+            let origin = self.storeys[self.storey].cell_center(x, y);
+            self.graphics
+                .push_square(origin, cell_width / 2.0, LIFT_COLOR, 0.0);
+        }
+
+        for light in self.storeys[self.storey].lights() {
+            let origin = to_world(light.pos);
+            self.graphics
+                .push_square(origin, LIGHT_MARKER_WIDTH, light.color, 45.0);
+        }
+
+        for sprite in &self.sprites {
+            if sprite.storey != self.storey {
+                continue;
+            }
+            self.graphics
+                .push_square(sprite.pos, SPRITE_MARKER_WIDTH, SPRITE_COLOR, 0.0);
+        }
     }
 
-    fn handle_wall(&mut self, handle: HandleWall) {
-        let n = self.map.len() as f32;
+    /// Draws the part of a door that has not slid open yet as a thin slab
+    /// across the middle of its cell.
+    fn draw_map_door(&mut self, x: usize, y: usize) {
+        let cell_width = self.map().cell_width();
+        let open = self.map().door(x, y).open;
+        let length = cell_width * (1.0 - open);
+        if length <= 0.0 {
+            return;
+        }
+        let [center_x, center_y] = self.map().cell_center(x, y);
+        // The door slides towards the high end of the axis it runs along,
+        // opening from the low end as rays find it
+        let shift = cell_width * open / 2.0;
+        let (origin, width, height) = if self.map().door_runs_along_x(x, y) {
+            ([center_x + shift, center_y], length, cell_width / 4.0)
+        } else {
+            ([center_x, center_y + shift], cell_width / 4.0, length)
+        };
+        let rect = Rect {
+            origin,
+            rotation: 0.0,
+            height,
+            width,
+        };
+        let tex_coords = Quad::texture(tile_slot(DOOR_TILE), [0.0, 1.0], [0.0, 1.0]);
+        self.graphics
+            .push_textured_rect(rect, WALL_COLOR, tex_coords);
+    }
+
+    /// Opens or closes the door directly in front of the player, or pushes the
+    /// push wall there away along whichever axis the player is facing closest to.
+    fn use_tile(&mut self) {
+        let rad = self.player.rotation.to_radians();
+        let reach = self.map().cell_width();
+        let target = [
+            self.player.pos[0] - reach * rad.sin(),
+            self.player.pos[1] + reach * rad.cos(),
+        ];
+        let [x, y] = self.map().to_map_space(target);
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if self.map().get(x, y) == Some(PUSH_WALL_TILE) {
+            let forward = [-rad.sin(), rad.cos()];
+            let direction = if forward[0].abs() > forward[1].abs() {
+                [forward[0].signum() as isize, 0]
+            } else {
+                [0, forward[1].signum() as isize]
+            };
+            self.map_mut().push_wall(x, y, direction);
+            return;
+        }
+        if !self.map().is_door(x, y) {
+            return;
+        }
+
+        // Never close a door on top of the player
+        let [player_x, player_y] = self.map().to_map_space(self.player.pos);
+        let player_inside = player_x as usize == x && player_y as usize == y;
+        let closing = matches!(
+            self.map().door(x, y).state,
+            DoorState::Open | DoorState::Opening
+        );
+        if player_inside && closing {
+            return;
+        }
+        self.map_mut().toggle_door(x, y);
+    }
+
+    /// Switches the level's fog between off, linear and exponential falloff,
+    /// on every storey alike.
+    fn cycle_fog(&mut self) {
+        let mut fog = self.map().fog();
+        fog.mode = match fog.mode {
+            FogMode::Off => FogMode::Linear,
+            FogMode::Linear => FogMode::Exponential,
+            FogMode::Exponential => FogMode::Off,
+        };
+        if fog.density <= 0.0 {
+            fog = Fog {
+                mode: fog.mode,
+                ..DEFAULT_FOG
+            };
+        }
+        for map in &mut self.storeys {
+            map.set_fog(fog);
+        }
+    }
+
+    /// Converts the mouse location into a world position on the map panel.
+    fn mouse_world_pos(&self) -> [f32; 2] {
         let width = self.graphics.gpu_state.size.width as f32;
         let height = self.graphics.gpu_state.size.height as f32;
-        let x = convert_range(self.mouse_location[0], [0.0, width], [0.0, n * 2.0]) as usize;
-        let y = n as usize
-            - 1
-            - convert_range(self.mouse_location[1], [0.0, height], [0.0, n]) as usize;
+        [
+            convert_range(self.mouse_location[0], [0.0, width / 2.0], [-1.0, 1.0]),
+            convert_range(self.mouse_location[1], [0.0, height], [1.0, -1.0]),
+        ]
+    }
+
+    fn handle_wall(&mut self, handle: HandleWall) {
+        let [mouse_x, mouse_y] = self.map().to_map_space(self.mouse_world_pos());
+        if mouse_x < 0.0 || mouse_y < 0.0 {
+            return;
+        }
+        let (x, y) = (mouse_x as usize, mouse_y as usize);
 
-        let player_x = convert_range(self.player.pos[0], [-1.0, 1.0], [0.0, n]);
-        let player_y = convert_range(self.player.pos[1], [-1.0, 1.0], [0.0, n]);
+        let [player_x, player_y] = self.map().to_map_space(self.player.pos);
 
         if player_x <= (x + 1) as f32
             && player_x >= x as f32
@@ -120,13 +495,29 @@ impl Game {
         {
             return;
         }
-        if x >= (n - 1.0) as usize || y >= (n - 1.0) as usize || x == 0 || y == 0 {
+        if x >= self.map().width() - 1 || y >= self.map().height() - 1 || x == 0 || y == 0 {
+            return;
+        }
+        if self.map().push_wall_at(x, y).is_some() {
             return;
         }
 
-        self.map[x][y] = match handle {
+        let tile = match handle {
             HandleWall::Destroy => 0,
-            HandleWall::Create => 1,
+            HandleWall::Create => self.selected_tile,
+        };
+        self.map_mut().set_tile(x, y, tile);
+        self.edited_storeys.insert(self.storey);
+    }
+
+    /// Bakes the lightmaps of the storeys edited since the last bake again, as
+    /// baked light would show through new walls and miss removed ones.
+    fn bake_edited_lightmaps(&mut self) {
+        for storey in self.edited_storeys.drain() {
+            let map = &mut self.storeys[storey];
+            if let Some(samples) = map.lightmap().map(|lightmap| lightmap.samples()) {
+                map.bake_lightmap(samples);
+            }
         }
     }
 
@@ -159,6 +550,7 @@ impl Game {
                 ..
             } => {
                 self.mouse_left = false;
+                self.bake_edited_lightmaps();
                 true
             }
 
@@ -178,6 +570,7 @@ impl Game {
                 ..
             } => {
                 self.mouse_right = false;
+                self.bake_edited_lightmaps();
                 true
             }
 
@@ -187,7 +580,9 @@ impl Game {
                     virtual_keycode: Some(VirtualKeyCode::Up),
                     ..
                 } => {
-                    self.player.move_forward(self.map);
+                    if !self.walk_through_portal(true) {
+                        self.player.move_forward(&self.storeys[self.storey]);
+                    }
                     true
                 }
                 KeyboardInput {
@@ -195,7 +590,9 @@ impl Game {
                     virtual_keycode: Some(VirtualKeyCode::Down),
                     ..
                 } => {
-                    self.player.move_backward(self.map);
+                    if !self.walk_through_portal(false) {
+                        self.player.move_backward(&self.storeys[self.storey]);
+                    }
                     true
                 }
 
@@ -221,73 +618,657 @@ impl Game {
                     true
                 }
 
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::PageUp),
+                    ..
+                } => {
+                    self.player.look(PITCH_STEP);
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::PageDown),
+                    ..
+                } => {
+                    self.player.look(-PITCH_STEP);
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::J),
+                    ..
+                } => {
+                    self.player.start_jump();
+                    true
+                }
+                // Crouching lasts for as long as the key is held
+                KeyboardInput {
+                    state,
+                    virtual_keycode: Some(VirtualKeyCode::C),
+                    ..
+                } => {
+                    self.player.crouching = *state == ElementState::Pressed;
+                    true
+                }
+
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::LBracket),
+                    ..
+                } => {
+                    self.fov = (self.fov - FOV_STEP).max(MIN_FOV);
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::RBracket),
+                    ..
+                } => {
+                    self.fov = (self.fov + FOV_STEP).min(MAX_FOV);
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::R),
+                    ..
+                } => {
+                    self.resolution = (self.resolution + 1) % RESOLUTIONS.len();
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::G),
+                    ..
+                } => {
+                    self.gpu_raycast = !self.gpu_raycast;
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::F),
+                    ..
+                } => {
+                    self.cycle_fog();
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::Space),
+                    ..
+                } => {
+                    self.use_tile();
+                    true
+                }
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                } => match tile_for_key(*key) {
+                    Some(tile) => {
+                        self.selected_tile = tile;
+                        true
+                    }
+                    None => false,
+                },
+
                 _ => false,
             },
             _ => false,
         }
     }
 
+    fn map(&self) -> &GameMap {
+        &self.storeys[self.storey]
+    }
+
+    fn map_mut(&mut self) -> &mut GameMap {
+        &mut self.storeys[self.storey]
+    }
+
+    /// Moves the player up or down a storey if they have just stepped into
+    /// a lift, landing them in the same cell of the other storey.
+    fn take_lift(&mut self) {
+        let cell = to_cell(self.map(), self.map().to_map_space(self.player.pos));
+        if cell == self.player_cell {
+            return;
+        }
+        self.player_cell = cell;
+        let Some([x, y]) = cell else {
+            return;
+        };
+        let target = match self.map().lift(x, y) {
+            Some(Lift::Up) => self.storey + 1,
+            Some(Lift::Down) => self.storey.wrapping_sub(1),
+            None => return,
+        };
+        if target < self.storeys.len() {
+            self.storey = target;
+        }
+    }
+
+    /// Carries the player through a portal if the step they are about to take
+    /// forward or backward walks them into one, turning them to match.
+    /// Returns whether they went through.
+    fn walk_through_portal(&mut self, forward: bool) -> bool {
+        let reach = MOVE_AMOUNT + self.player.width / 2.0;
+        let ray = Ray {
+            origin: self.player.pos,
+            length: reach,
+            rotation: if forward {
+                self.player.rotation
+            } else {
+                self.player.rotation + 180.0
+            },
+        };
+        let map = self.map();
+        let Some(hit) = ray.collision(map, reach) else {
+            return false;
+        };
+        let Some(portal) = map.portal(hit.cell, hit.face) else {
+            return false;
+        };
+        let Some(target) = self.storeys.get(portal.storey) else {
+            return false;
+        };
+
+        // Come out as far from the far face as the step would have taken the
+        // player past the near one
+        let turn = portal.turn(hit.face);
+        let point = portal.carry(hit.cell, hit.face, map.to_map_space(hit.point));
+        let out = Ray {
+            origin: target.to_world_space(point),
+            rotation: ray.rotation + turn,
+            ..ray
+        };
+        let pos = out.point_at(reach - hit.distance + self.player.width / 2.0);
+        let Some([x, y]) = to_cell(target, target.to_map_space(pos)) else {
+            return false;
+        };
+        if target.blocks_movement(x, y) {
+            return false;
+        }
+
+        self.storey = portal.storey;
+        self.player_cell = Some([x, y]);
+        self.player.pos = pos;
+        self.player.view.origin = pos;
+        self.player.rotation = (self.player.rotation + turn).rem_euclid(360.0);
+        true
+    }
+
+    fn camera(&self) -> Camera {
+        Camera {
+            horizon: -self.player.pitch,
+            eye: self.player.eye_height(),
+            ..Camera::new(self.player.pos, self.player.rotation, self.fov)
+        }
+    }
+
+    /// Number of columns the 3D view is drawn with, either the chosen render
+    /// resolution or one per pixel of the right half of the window.
+    fn columns(&self) -> usize {
+        let viewport_width = self.graphics.gpu_state.size.width as usize / 2;
+        RESOLUTIONS[self.resolution]
+            .unwrap_or(viewport_width)
+            .max(1)
+    }
+
+    /// The ray through the middle of each column of the 3D view, from the
+    /// left edge of the screen to the right.
+    fn column_rays(&self) -> Vec<Ray> {
+        let camera = self.camera();
+        let columns = self.columns();
+        let column_width = COORD_SIZE as f32 / columns as f32;
+        (0..columns)
+            .map(|column| camera.ray(-1.0 + column_width * (column as f32 + 0.5)))
+            .collect()
+    }
+
+    /// Casts one ray through the middle of each column of the 3D view, from
+    /// the left edge of the screen to the right, on every storey.
     fn cast_rays(&mut self) {
-        let view_angle = 60;
-        for deg in (-view_angle..=view_angle).step_by(1) {
-            let angle = (deg as f32 / 2.0) % 360.0;
-            let ray = Ray {
-                rotation: self.player.rotation + angle as f32,
-                origin: self.player.pos,
-                length: 0.0,
-            };
-            let (ray_length, lighting) = ray.collision(self.map);
-            let color = match lighting {
-                Lighting::Shaded => SHADED_COLOR,
-                Lighting::Lit => LIT_COLOR,
-            };
-            let fix_fisheye = |length: f32| length * (angle as f32).to_radians().cos();
-            self.ray_data.push((fix_fisheye(ray_length), lighting));
+        let camera = self.camera();
+        let columns = self.columns();
+        let column_width = COORD_SIZE as f32 / columns as f32;
+        let screen_x = |column: usize| -1.0 + column_width * (column as f32 + 0.5);
+        let rays = self.column_rays();
+
+        for storey in 0..self.storeys.len() {
+            // The floor of each leg is cast up to wherever it stopped or went
+            // into a portal or mirror
+            let maps = &self.storeys;
+            // Everything seen through portals is drawn at this storey's height
+            let eye = camera.eye - self.storey_offset(storey);
+            let cast = map_rays(&rays, |ray| {
+                let legs = ray.legs(maps, storey, eye, VIEW_DISTANCE, MIRROR_BOUNCES);
+                let spans: Vec<Vec<CellSpan>> = legs
+                    .iter()
+                    .map(|leg| leg.ray.spans(&maps[leg.storey], eye, leg.length))
+                    .collect();
+                (legs, spans)
+            });
+
+            let mut storey_columns = Vec::with_capacity(columns);
+            for (column, (legs, spans)) in cast.into_iter().enumerate() {
+                let fix_fisheye = |length: f32| camera.depth(screen_x(column), length);
+                let mut data = Column {
+                    hits: vec![],
+                    depth: fix_fisheye(legs[0].length),
+                    spans: vec![],
+                };
+                for (leg, spans) in legs.iter().zip(spans) {
+                    let tint = mirror_tint(leg.bounces);
+                    let hits = leg.passed.iter().chain(&leg.stop).map(|hit| {
+                        let distance = hit.distance + leg.start;
+                        ColumnHit {
+                            depth: fix_fisheye(distance),
+                            storey: leg.storey,
+                            tint,
+                            hit: RayHit {
+                                distance,
+                                exit: hit.exit + leg.start,
+                                ..*hit
+                            },
+                        }
+                    });
+                    data.hits.extend(hits);
+                    data.spans.extend(spans.into_iter().map(|span| ColumnSpan {
+                        storey: leg.storey,
+                        tint,
+                        span: CellSpan {
+                            entry: fix_fisheye(span.entry + leg.start),
+                            exit: fix_fisheye(span.exit + leg.start),
+                            ..span
+                        },
+                    }));
+                }
+                if storey == self.storey {
+                    self.push_map_rays(&legs);
+                }
+                storey_columns.push(data);
+            }
+            self.ray_data.push(storey_columns);
+        }
+    }
+
+    /// Casts only the rays shown on the map, for when the 3D view is raycast
+    /// on the GPU.
+    fn cast_map_rays(&mut self) {
+        let eye = self.camera().eye;
+        let maps = &self.storeys;
+        let storey = self.storey;
+        let cast = map_rays(&self.column_rays(), |ray| {
+            ray.legs(maps, storey, eye, VIEW_DISTANCE, MIRROR_BOUNCES)
+        });
+        for legs in cast {
+            self.push_map_rays(&legs);
+        }
+    }
+
+    /// Shows the legs of a ray cast from the player's storey that stay on it
+    /// on the map, each shaded like the face that stopped it.
+    fn push_map_rays(&mut self, legs: &[Leg]) {
+        for leg in legs.iter().filter(|leg| leg.storey == self.storey) {
+            let color = leg.stop.map_or(RAY_COLOR, |hit| {
+                tinted(RAY_COLOR, face_shades(hit.tile).shade(hit.face))
+            });
             self.graphics.push_ray(
                 Ray {
-                    length: ray_length,
-                    origin: self.player.pos,
-                    rotation: self.player.rotation + angle,
+                    length: leg.length,
+                    ..leg.ray
                 },
                 color,
             )
         }
     }
 
-    fn draw_walls(&mut self) {
+    /// How far the floor of a storey is above the floor of the player's
+    /// storey, in standard wall heights.
+    fn storey_offset(&self, storey: usize) -> f32 {
+        storey as f32 - self.storey as f32
+    }
+
+    /// Draws a flat ground and sky behind everything, meeting at the
+    /// horizon, which show through wherever a cell has no floor or ceiling.
+    fn draw_backdrop(&mut self) {
+        let horizon = self.camera().horizon;
         let ground = Rect {
             rotation: 0.0,
-            origin: [0.0, -0.5],
-            height: 1.0,
+            origin: [0.0, (horizon - 1.0) / 2.0],
+            height: 1.0 + horizon,
             width: 2.0,
         };
         let sky = Rect {
             rotation: 0.0,
-            origin: [0.0, 0.5],
-            height: 1.0,
+            origin: [0.0, (horizon + 1.0) / 2.0],
+            height: 1.0 - horizon,
             width: 2.0,
         };
         self.graphics.push_rect_right(ground, GROUND_COLOR);
         self.graphics.push_rect_right(sky, SKY_COLOR);
-        let n = self.ray_data.len();
-        let column_width = COORD_SIZE as f32 / n as f32;
-        for i in 0..n {
-            let (ray_length, lighting) = self.ray_data.get(i).unwrap_or(&(0.0, Lighting::Lit));
-            let color = match lighting {
-                Lighting::Shaded => SHADED_COLOR,
-                Lighting::Lit => LIT_COLOR,
+    }
+
+    /// Casts the floors, or the ceilings, of every cell a column's ray crosses.
+    /// Only floors below the eye and ceilings above it can be seen. Each is
+    /// queued at its far edge, so that anything standing on it covers it.
+    fn draw_planes(&mut self, ceilings: bool) {
+        let camera = self.camera();
+        let wall_height = WALL_HEIGHT * camera.zoom();
+        let horizon = camera.horizon;
+        for (storey, columns) in self.ray_data.iter().enumerate() {
+            // A plane `rise` above the eye is drawn rise / d above the horizon at
+            // distance d, so anything nearer than where that reaches the edge
+            // of the screen is off it
+            let offset = self.storey_offset(storey);
+            let (rise, visible, edge) = if ceilings {
+                let rise = (offset + 1.0 - camera.eye) * wall_height;
+                (rise, rise > 0.0, 1.0 - horizon)
+            } else {
+                let rise = (offset - camera.eye) * wall_height;
+                (rise, rise < 0.0, 1.0 + horizon)
             };
-            let height = 0.5 / ray_length;
-            let rect = Rect {
-                origin: [
-                    (-1.0 + (column_width / 2.0)) + (column_width * (n - i - 1) as f32),
-                    0.0,
-                ],
-                rotation: 0.0,
-                height,
-                width: column_width,
+            if !visible {
+                continue;
+            }
+            let n = columns.len();
+            let column_width = COORD_SIZE as f32 / n as f32;
+            for (column, data) in columns.iter().enumerate() {
+                let left = -1.0 + column_width * column as f32;
+                let right = left + column_width;
+                for ColumnSpan {
+                    storey: span_storey,
+                    tint,
+                    span,
+                } in &data.spans
+                {
+                    let map = &self.storeys[*span_storey];
+                    let [i, j] = span.cell;
+                    let tile = if ceilings {
+                        map.ceiling(i, j)
+                    } else {
+                        map.floor(i, j)
+                    };
+                    let (near, far) = (span.entry.max(rise.abs() / edge), span.exit);
+                    if tile == 0 || far <= near {
+                        continue;
+                    }
+
+                    let slot = tile_slot(tile);
+                    let point_at = |distance: f32| {
+                        let t = (distance - span.entry) / (span.exit - span.entry);
+                        map.to_map_space([
+                            span.entry_point[0] + (span.exit_point[0] - span.entry_point[0]) * t,
+                            span.entry_point[1] + (span.exit_point[1] - span.entry_point[1]) * t,
+                        ])
+                    };
+                    let uv_at = |distance: f32| {
+                        let [x, y] = point_at(distance);
+                        atlas_uv(slot, x - i as f32, 1.0 - (y - j as f32))
+                    };
+                    // Lit cells are drawn in pieces, each lit at its middle
+                    let pieces = if map.lights().is_empty() {
+                        1
+                    } else {
+                        LIGHT_SAMPLES
+                    };
+                    let piece_length = (far - near) / pieces as f32;
+                    for piece in 0..pieces {
+                        let near = near + piece_length * piece as f32;
+                        let far = near + piece_length;
+                        let light =
+                            self.light_grids[*span_storey].at(point_at(near + piece_length / 2.0));
+                        let (near_uv, far_uv) = (uv_at(near), uv_at(far));
+                        let (near_y, far_y) = (horizon + rise / near, horizon + rise / far);
+                        // Corners are listed top first, which is the far edge of a
+                        // floor and the near edge of a ceiling
+                        let (quad, tex_coords, depths) = if ceilings {
+                            (
+                                Quad {
+                                    tl: [left, near_y],
+                                    bl: [left, far_y],
+                                    br: [right, far_y],
+                                    tr: [right, near_y],
+                                },
+                                Quad {
+                                    tl: near_uv,
+                                    bl: far_uv,
+                                    br: far_uv,
+                                    tr: near_uv,
+                                },
+                                [near, far, far, near],
+                            )
+                        } else {
+                            (
+                                Quad {
+                                    tl: [left, far_y],
+                                    bl: [left, near_y],
+                                    br: [right, near_y],
+                                    tr: [right, far_y],
+                                },
+                                Quad {
+                                    tl: far_uv,
+                                    bl: near_uv,
+                                    br: near_uv,
+                                    tr: far_uv,
+                                },
+                                [far, near, near, far],
+                            )
+                        };
+                        self.strips.push(Strip {
+                            depth: far,
+                            quad,
+                            color: tinted(tinted(LIT_TINT, light), *tint),
+                            tex_coords,
+                            depths,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues every hit of every column to be drawn, including see-through
+    /// tiles and short walls in front of the wall that stopped the ray.
+    fn draw_walls(&mut self) {
+        let camera = self.camera();
+        let wall_height = WALL_HEIGHT * camera.zoom();
+        for (storey, columns) in self.ray_data.iter().enumerate() {
+            let offset = self.storey_offset(storey);
+            let n = columns.len();
+            let column_width = COORD_SIZE as f32 / n as f32;
+            for (i, column) in columns.iter().enumerate() {
+                let left = -1.0 + column_width * i as f32;
+                let right = left + column_width;
+                for &ColumnHit {
+                    depth: ray_length,
+                    storey: hit_storey,
+                    tint,
+                    hit,
+                } in &column.hits
+                {
+                    let color = face_shades(hit.tile).shade(hit.face);
+                    let map = &self.storeys[hit_storey];
+                    let light = light_at(map, map.to_map_space(hit.point), Some(hit.face));
+                    let color = tinted(tinted(color, light), tint);
+                    let slot = tile_slot(hit.tile);
+                    // The wall stands on its storey's floor, with its texture
+                    // repeating once per standard height
+                    let bottom = camera.horizon + (offset - camera.eye) * wall_height / ray_length;
+                    let unit = wall_height / ray_length;
+                    let mut base = 0.0;
+                    while base < hit.height {
+                        let top = hit.height.min(base + 1.0);
+                        let quad = Quad {
+                            tl: [left, bottom + unit * top],
+                            bl: [left, bottom + unit * base],
+                            br: [right, bottom + unit * base],
+                            tr: [right, bottom + unit * top],
+                        };
+                        let v = [1.0 - (top - base), 1.0];
+                        let tex_coords = Quad::texture(slot, [hit.tex_x; 2], v);
+                        self.strips.push(Strip {
+                            depth: ray_length,
+                            quad,
+                            color,
+                            tex_coords,
+                            depths: [ray_length; 4],
+                        });
+                        base = top;
+                    }
+
+                    // Walls lower than the eye show their top, out to where the
+                    // ray leaves the cell
+                    let drop = (camera.eye - offset - hit.height) * wall_height;
+                    if drop <= 0.0 {
+                        continue;
+                    }
+                    let scale = ray_length / hit.distance;
+                    let (entry, exit) = (ray_length, hit.exit * scale);
+                    let near = entry.max(drop / (1.0 + camera.horizon));
+                    if exit <= near {
+                        continue;
+                    }
+                    let [i, j] = hit.cell;
+                    let point_at = |depth: f32| {
+                        let t = (depth - entry) / (exit - entry);
+                        map.to_map_space([
+                            hit.point[0] + (hit.exit_point[0] - hit.point[0]) * t,
+                            hit.point[1] + (hit.exit_point[1] - hit.point[1]) * t,
+                        ])
+                    };
+                    let uv_at = |depth: f32| {
+                        let [x, y] = point_at(depth);
+                        let [u, v] = [x - i as f32, 1.0 - (y - j as f32)];
+                        atlas_uv(slot, u.clamp(0.0, 1.0), v.clamp(0.0, 1.0))
+                    };
+                    let (near_uv, far_uv) = (uv_at(near), uv_at(exit));
+                    let (near_y, far_y) =
+                        (camera.horizon - drop / near, camera.horizon - drop / exit);
+                    let quad = Quad {
+                        tl: [left, far_y],
+                        bl: [left, near_y],
+                        br: [right, near_y],
+                        tr: [right, far_y],
+                    };
+                    let tex_coords = Quad {
+                        tl: far_uv,
+                        bl: near_uv,
+                        br: near_uv,
+                        tr: far_uv,
+                    };
+                    let light = self.light_grids[hit_storey].at(point_at((near + exit) / 2.0));
+                    self.strips.push(Strip {
+                        depth: ray_length,
+                        quad,
+                        color: tinted(tinted(LIT_TINT, light), tint),
+                        tex_coords,
+                        depths: [exit, near, near, exit],
+                    });
+                }
+            }
+        }
+    }
+
+    /// Queues sprites to be drawn as camera facing billboards. Each column of a
+    /// sprite is only drawn if it is nearer than the wall that stopped that
+    /// column's ray on the sprite's storey.
+    fn draw_sprites(&mut self) {
+        let camera = self.camera();
+        let wall_height = WALL_HEIGHT * camera.zoom();
+
+        for sprite in &self.sprites {
+            let Some(columns) = self.ray_data.get(sprite.storey) else {
+                continue;
+            };
+            let n = columns.len();
+            let column_width = COORD_SIZE as f32 / n as f32;
+            let cell_width = self.storeys[sprite.storey].cell_width();
+            let Some((centre, depth)) = camera.project(sprite.pos) else {
+                continue;
             };
-            self.graphics.push_rect_right(rect, color)
+            let half_width = camera.screen_width(sprite.scale * cell_width / 2.0, depth);
+            let left = centre - half_width;
+            let right = centre + half_width;
+            if right <= -1.0 || left >= 1.0 {
+                continue;
+            }
+
+            let map = &self.storeys[sprite.storey];
+            let color = tinted(
+                LIT_TINT,
+                self.light_grids[sprite.storey].at(map.to_map_space(sprite.pos)),
+            );
+            let offset = self.storey_offset(sprite.storey);
+            let bottom = camera.horizon + (offset - camera.eye) * wall_height / depth;
+            let top = bottom + wall_height * sprite.scale / depth;
+
+            let first = (((left + 1.0) / column_width).floor().max(0.0)) as usize;
+            let last = (((right + 1.0) / column_width).ceil() as usize).min(n);
+            for (column, data) in columns.iter().enumerate().take(last).skip(first) {
+                if depth >= data.depth {
+                    continue;
+                }
+                let column_left = (-1.0 + column_width * column as f32).max(left);
+                let column_right = (-1.0 + column_width * (column + 1) as f32).min(right);
+                let u = [
+                    (column_left - left) / (right - left),
+                    (column_right - left) / (right - left),
+                ];
+                let quad = Quad {
+                    tl: [column_left, top],
+                    bl: [column_left, bottom],
+                    br: [column_right, bottom],
+                    tr: [column_right, top],
+                };
+                let tex_coords = Quad::texture(sprite.texture, u, [0.0, 1.0]);
+                self.strips.push(Strip {
+                    depth,
+                    quad,
+                    color,
+                    tex_coords,
+                    depths: [depth; 4],
+                });
+            }
+        }
+    }
+
+    /// The frame for the GPU raycaster to draw, with the sprites lit as the
+    /// CPU path lights them.
+    fn raycast_frame(&self) -> Option<RaycastFrame> {
+        let sprites: Vec<_> = self
+            .sprites
+            .iter()
+            .filter_map(|sprite| {
+                let map = self.storeys.get(sprite.storey)?;
+                let light = self.light_grids[sprite.storey].at(map.to_map_space(sprite.pos));
+                Some((*sprite, tinted(LIT_TINT, light)))
+            })
+            .collect();
+        let (camera, columns) = (self.camera(), self.columns());
+        let max_texture_size = self.graphics.gpu_state.max_texture_size();
+        RaycastFrame::new(
+            &self.storeys,
+            self.storey,
+            &camera,
+            &sprites,
+            columns,
+            VIEW_DISTANCE,
+            max_texture_size,
+        )
+    }
+
+    /// Draws the queued floors, walls and sprites furthest first, so that
+    /// nearer ones cover them and show them through any transparent parts.
+    fn draw_strips(&mut self) {
+        self.strips.sort_by(|a, b| b.depth.total_cmp(&a.depth));
+        for strip in self.strips.drain(..) {
+            self.graphics.push_textured_quad_right(
+                strip.quad,
+                strip.color,
+                strip.tex_coords,
+                strip.depths,
+            );
         }
     }
 
@@ -307,13 +1288,305 @@ impl Game {
     }
 
     pub fn update(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.player.update_jump();
+        for map in &mut self.storeys {
+            map.update_doors(DOOR_SPEED);
+            map.update_push_walls(PUSH_WALL_SPEED);
+        }
+        self.take_lift();
+        self.draw()
+    }
+
+    /// Draws the map and the 3D view as things stand, without moving anything.
+    fn draw(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.graphics.gpu_state.set_fog(self.map().fog());
         self.draw_map();
         self.push_player();
-        self.cast_rays();
-        self.draw_walls();
+        // The light only changes as doors and push walls move or the map is
+        // edited, which leaves it the same for most frames
+        if self.lit_storeys != self.storeys {
+            self.light_grids = self
+                .storeys
+                .iter()
+                .map(|map| LightGrid::new(map, LIGHT_SAMPLES))
+                .collect();
+            self.lit_storeys = self.storeys.clone();
+            self.gpu_lights_stale = true;
+        }
+        // Frames the shader cannot hold are drawn on the CPU instead
+        let frame = self.gpu_raycast.then(|| self.raycast_frame()).flatten();
+        self.graphics.gpu_state.set_raycast(frame.as_ref());
+        if frame.is_some() {
+            if self.gpu_lights_stale {
+                let lights = RaycastLights::new(&self.storeys, &self.light_grids);
+                self.graphics.gpu_state.set_raycast_lights(&lights);
+                self.gpu_lights_stale = false;
+            }
+            self.cast_map_rays();
+        } else {
+            self.cast_rays();
+            self.draw_backdrop();
+            self.draw_walls();
+            // Queued after the walls so that a ceiling hides the top of a wall
+            // reaching above it, and a floor hides the wall of the storey
+            // below it, even where they meet at the same distance
+            self.draw_planes(false);
+            self.draw_planes(true);
+            self.draw_sprites();
+        }
+        self.draw_strips();
         let err = self.graphics.draw();
         self.graphics.clear();
         self.ray_data.clear();
         err
     }
 }
+
+/// The tint of something seen after bouncing off `bounces` mirrors.
+fn mirror_tint(bounces: usize) -> [f32; 3] {
+    MIRROR_TINT.map(|c| c.powi(bounces as i32))
+}
+
+fn tinted(color: [f32; 3], tint: [f32; 3]) -> [f32; 3] {
+    [color[0] * tint[0], color[1] * tint[1], color[2] * tint[2]]
+}
+
+/// The cell of a map containing a point in map space, or None if the point
+/// is off the map.
+fn to_cell(map: &GameMap, [x, y]: [f32; 2]) -> Option<[usize; 2]> {
+    let inside = (0.0..map.width() as f32).contains(&x) && (0.0..map.height() as f32).contains(&y);
+    inside.then_some([x as usize, y as usize])
+}
+
+/// The tile placed by left clicking after pressing one of the number keys.
+fn tile_for_key(key: VirtualKeyCode) -> Option<u8> {
+    match key {
+        VirtualKeyCode::Key1 => Some(1),
+        VirtualKeyCode::Key2 => Some(2),
+        VirtualKeyCode::Key3 => Some(3),
+        VirtualKeyCode::Key4 => Some(4),
+        VirtualKeyCode::Key5 => Some(5),
+        VirtualKeyCode::Key6 => Some(6),
+        VirtualKeyCode::Key7 => Some(7),
+        VirtualKeyCode::Key8 => Some(DOOR_TILE),
+        VirtualKeyCode::Key9 => Some(PUSH_WALL_TILE),
+        VirtualKeyCode::Key0 => Some(WINDOW_TILE),
+        VirtualKeyCode::Minus => Some(GRATE_TILE),
+        VirtualKeyCode::Equals => Some(FENCE_TILE),
+        VirtualKeyCode::M => Some(MIRROR_TILE),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::WGPUState;
+    use crate::texture::{BARREL_SLOT, PLANT_SLOT};
+
+    /// Size of the frames drawn offscreen, whose right half is the 3D view.
+    const FRAME_SIZE: [u32; 2] = [512, 256];
+
+    /// A game drawing offscreen, or None if there is no adapter to draw with.
+    fn offscreen_game() -> Option<Game> {
+        let [width, height] = FRAME_SIZE;
+        let size = winit::dpi::PhysicalSize::new(width, height);
+        let Some(state) = pollster::block_on(WGPUState::offscreen(size)) else {
+            eprintln!("skipping, as there is no adapter to draw with");
+            return None;
+        };
+        Some(Game::new(Graphics::new(state)))
+    }
+
+    /// A storey with every kind of wall the GPU raycaster draws: see-through
+    /// tiles, a part open door, a sliding push wall, segment walls and walls
+    /// lower and taller than the rest, under a ceiling open to the sky in
+    /// places. A balcony above the north of it looks down into the open south,
+    /// portals lead from its west wall onto the balcony and from its east wall
+    /// round to its north wall, and mirrors face each other across it. A baked
+    /// lamp lights it, and a lamp that is traced every frame the balcony.
+    fn test_level() -> (Vec<GameMap>, Vec<Sprite>) {
+        let mut map = GameMap::bordered(10, 10);
+        map.fill_floor_and_ceiling(DEFAULT_FLOOR, DEFAULT_CEILING);
+        let mut balcony = GameMap::bordered(10, 10);
+        for x in 1..9 {
+            map[(x, 3)] = 1;
+            map.set_ceiling(x, 8, 0);
+            for y in 0..3 {
+                map.set_ceiling(x, y, 0);
+            }
+            balcony[(x, 4)] = 2;
+            balcony.set_wall_height(x, 4, 0.25);
+            for y in 4..8 {
+                balcony.set_floor(x, y, DEFAULT_FLOOR);
+            }
+        }
+        balcony[(3, 6)] = WINDOW_TILE;
+        balcony[(6, 6)] = 5;
+        balcony.set_ambient(AMBIENT_LIGHT);
+        balcony.add_light(Light::new([7.5, 6.5], [0.6, 0.7, 1.0], 3.0, 0.8));
+        balcony.set_fog(DEFAULT_FOG);
+        map[(2, 3)] = WINDOW_TILE;
+        map[(3, 3)] = WINDOW_TILE;
+        map[(4, 3)] = GRATE_TILE;
+        map[(5, 3)] = DOOR_TILE;
+        map[(7, 3)] = FENCE_TILE;
+        map.toggle_door(5, 3);
+        map.update_doors(0.4);
+        map[(1, 7)] = PUSH_WALL_TILE;
+        map.push_wall(1, 7, [1, 0]);
+        map.update_push_walls(0.4);
+        map.add_segment(Segment::new([6.5, 7.5], [7.5, 8.5], 2));
+        map[(3, 6)] = 2;
+        map.set_wall_height(3, 6, 0.25);
+        map[(6, 5)] = 3;
+        map.set_wall_height(6, 5, 0.5);
+        map[(8, 8)] = 4;
+        map.set_wall_height(8, 8, 2.0);
+        let portal = |storey, cell, face| Some(Portal { storey, cell, face });
+        map.set_portal([0, 5], Face::East, portal(1, [9, 6], Face::West));
+        map.set_portal([9, 1], Face::West, portal(0, [4, 9], Face::South));
+        map[(0, 6)] = MIRROR_TILE;
+        map[(9, 5)] = MIRROR_TILE;
+        map[(9, 6)] = MIRROR_TILE;
+        map.set_ambient(AMBIENT_LIGHT);
+        map.add_light(Light::new([5.5, 5.5], [1.0, 0.8, 0.6], 4.0, 0.9));
+        map.bake_lightmap(LIGHT_SAMPLES);
+        map.set_fog(DEFAULT_FOG);
+
+        let sprites = vec![
+            Sprite::new(map.cell_center(4, 5), BARREL_SLOT),
+            Sprite::new(map.cell_center(5, 1), PLANT_SLOT),
+            Sprite {
+                storey: 1,
+                ..Sprite::new(balcony.cell_center(5, 5), PLANT_SLOT)
+            },
+        ];
+        (vec![map, balcony], sprites)
+    }
+
+    /// The 3D view of a frame, drawn on the CPU or the GPU.
+    fn draw_view(game: &mut Game, gpu: bool) -> Vec<[u8; 4]> {
+        game.gpu_raycast = gpu;
+        game.draw().unwrap();
+        let [width, _] = FRAME_SIZE;
+        let pixels = game.graphics.gpu_state.read_pixels();
+        pixels
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(i, _)| (*i as u32 % width) >= width / 2)
+            .map(|(_, pixel)| pixel.try_into().unwrap())
+            .collect()
+    }
+
+    /// How much of the view one frame draws noticeably differently from the
+    /// other, counting only pixels unlike any of their neighbours in the other
+    /// frame so that edges landing a pixel apart are not counted.
+    fn difference(a: &[[u8; 4]], b: &[[u8; 4]]) -> f32 {
+        let width = FRAME_SIZE[0] as usize / 2;
+        let height = a.len() / width;
+        let alike = |a: [u8; 4], b: [u8; 4]| a.iter().zip(b).all(|(a, b)| a.abs_diff(b) <= 48);
+        let differs = |a: &[[u8; 4]], b: &[[u8; 4]], i: usize| {
+            let (x, y) = (i % width, i / width);
+            let near = |v: usize, size: usize| v.saturating_sub(1)..(v + 2).min(size);
+            !near(y, height).any(|ny| near(x, width).any(|nx| alike(a[i], b[ny * width + nx])))
+        };
+        let count = (0..a.len())
+            .filter(|&i| differs(a, b, i) || differs(b, a, i))
+            .count();
+        count as f32 / a.len() as f32
+    }
+
+    /// Draws the 3D view on the CPU and the GPU with the player on each
+    /// storey, at each position in map space, rotation, pitch and jump height,
+    /// and checks that the two match but for a few pixels.
+    fn assert_views_match(game: &mut Game, views: &[(usize, [f32; 2], f32, f32, f32)]) {
+        for &(storey, pos, rotation, pitch, jump) in views {
+            game.storey = storey;
+            game.player.pos = game.map().to_world_space(pos);
+            game.player.rotation = rotation;
+            game.player.pitch = pitch;
+            game.player.jump = jump;
+            let cpu = draw_view(game, false);
+            let gpu = draw_view(game, true);
+            let difference = difference(&cpu, &gpu);
+            assert!(
+                difference < 0.02,
+                "{:.1}% of the view differs from {pos:?} on storey {storey} facing {rotation}",
+                difference * 100.0
+            );
+        }
+    }
+
+    #[test]
+    fn the_edge_of_a_map_without_a_border_stops_the_player() {
+        let map = GameMap::new(10, 10);
+        let pos = map.cell_center(5, 0);
+        let mut player = Player {
+            pos,
+            width: PLAYER_WIDTH,
+            rotation: 180.0,
+            view: Ray {
+                origin: pos,
+                length: LINE_LENGTH,
+                rotation: 180.0,
+            },
+            pitch: 0.0,
+            jump: 0.0,
+            jump_speed: 0.0,
+            crouching: false,
+        };
+        for _ in 0..100 {
+            player.move_forward(&map);
+        }
+        assert!(player.pos[1] - PLAYER_WIDTH / 2.0 >= -1.0);
+        assert_eq!(to_cell(&map, map.to_map_space(player.pos)), Some([5, 0]));
+        assert_eq!(to_cell(&map, [5.5, -0.5]), None);
+    }
+
+    #[test]
+    fn levels_too_big_for_the_gpu_textures_are_drawn_on_the_cpu() {
+        let Some(mut game) = offscreen_game() else {
+            return;
+        };
+        // Fits the map texture but not the light texture a browser allows
+        let map = GameMap::bordered(400, 12);
+        game.player.pos = map.cell_center(200, 6);
+        game.player_cell = Some([200, 6]);
+        (game.storeys, game.sprites) = (vec![map], vec![]);
+        game.gpu_raycast = true;
+        assert!(game.raycast_frame().is_none());
+        game.draw().unwrap();
+    }
+
+    #[test]
+    fn the_gpu_raycaster_draws_what_the_cpu_does() {
+        let Some(mut game) = offscreen_game() else {
+            return;
+        };
+        (game.storeys, game.sprites) = test_level();
+        assert_views_match(
+            &mut game,
+            &[
+                // Through the window, grate, door and fence from the south
+                (0, [4.5, 1.5], 0.0, 0.0, 0.0),
+                (0, [2.2, 1.2], 340.0, 0.2, 0.0),
+                // Over the low walls at the push wall and the tall wall
+                (0, [5.5, 4.5], 30.0, -0.3, 0.3),
+                (0, [2.5, 4.5], 300.0, 0.0, 0.0),
+                // Back at the see-through tiles from the north, looking down on them
+                (0, [6.5, 8.5], 160.0, -0.4, 0.6),
+                // Up at the balcony from the south, and down from it
+                (0, [4.5, 0.5], 20.0, 0.5, 0.0),
+                (1, [4.5, 6.5], 190.0, -0.5, 0.0),
+                (1, [2.5, 7.5], 135.0, -0.2, 0.2),
+                // Through the portals onto the balcony and round to the north wall
+                (0, [2.5, 5.5], 80.0, 0.0, 0.0),
+                (0, [7.5, 1.5], 250.0, 0.3, 0.0),
+                // Into the mirrors, and between them
+                (0, [6.5, 4.5], 300.0, -0.2, 0.3),
+                (0, [4.5, 6.5], 265.0, 0.0, 0.0),
+            ],
+        );
+    }
+}
@@ -1,14 +1,18 @@
-use crate::vertex;
+use crate::{
+    fog::{Fog, FogUniform},
+    gpu_raycast::{RaycastFrame, RaycastLights, ViewUniform},
+    texture::Atlas,
+    vertex,
+};
 use vertex::Vertex;
+use wgpu::util::DeviceExt;
 use winit::window::Window;
 
 pub struct WGPUState {
-    surface: wgpu::Surface,
+    target: Target,
     device: wgpu::Device,
     queue: wgpu::Queue,
-    config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
-    window: Window,
     render_pipeline: wgpu::RenderPipeline,
     line_render_pipeline: wgpu::RenderPipeline,
     line_vertex_buffer: wgpu::Buffer,
@@ -17,18 +21,40 @@ pub struct WGPUState {
     tri_num_vertices: u32,
     tri_index_buffer: wgpu::Buffer,
     pub tri_num_indices: u32,
+    atlas_bind_group: wgpu::BindGroup,
+    fog_buffer: wgpu::Buffer,
+    fog_bind_group: wgpu::BindGroup,
+    raycast_pipeline: wgpu::RenderPipeline,
+    raycast_bind_group_layout: wgpu::BindGroupLayout,
+    raycast_bind_group: wgpu::BindGroup,
+    map_texture: wgpu::Texture,
+    light_texture: wgpu::Texture,
+    view_buffer: wgpu::Buffer,
+    /// Whether the 3D view is raycast on the GPU this frame.
+    raycast: bool,
     pub aspect_ratio: f32,
 }
 
-pub const MAX_VERTICES: u64 = 4000;
-pub const MAX_INDICES: u64 = 6000;
+/// Where frames are drawn.
+enum Target {
+    Window {
+        surface: wgpu::Surface,
+        config: wgpu::SurfaceConfiguration,
+        window: Window,
+    },
+    /// A texture read back once drawn, for tests, which have no window.
+    #[cfg(test)]
+    Texture(wgpu::Texture),
+}
+
+/// Vertices and indices the buffers start with room for. They grow to fit
+/// whatever a frame needs.
+pub const INITIAL_VERTICES: u64 = 65536;
+pub const INITIAL_INDICES: u64 = 98304;
 
 impl WGPUState {
     pub async fn new(window: Window) -> Self {
         let size = window.inner_size();
-        let num_vertices = 0;
-        let line_num_vertices = 0;
-        let aspect_ratio = window.inner_size().height as f32 / window.inner_size().width as f32;
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::all(),
             dx12_shader_compiler: Default::default(),
@@ -45,21 +71,12 @@ impl WGPUState {
             .await
             .unwrap();
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
-                    label: None,
-                },
-                None,
-            )
-            .await
-            .unwrap();
+        let limits = if cfg!(target_arch = "wasm32") {
+            wgpu::Limits::downlevel_webgl2_defaults()
+        } else {
+            wgpu::Limits::default()
+        };
+        let (device, queue) = request_device(&adapter, limits).await;
 
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps
@@ -81,15 +98,289 @@ impl WGPUState {
 
         surface.configure(&device, &config);
 
+        let format = config.format;
+        let target = Target::Window {
+            surface,
+            config,
+            window,
+        };
+        Self::with_target(device, queue, format, size, target)
+    }
+
+    /// A state drawing into a texture instead of a window, on a software
+    /// adapter where there is one. None if there is no adapter at all.
+    #[cfg(test)]
+    pub async fn offscreen(size: winit::dpi::PhysicalSize<u32>) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            dx12_shader_compiler: Default::default(),
+        });
+        let fallback = wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..Default::default()
+        };
+        let adapter = match instance.request_adapter(&fallback).await {
+            Some(adapter) => adapter,
+            None => instance.request_adapter(&Default::default()).await?,
+        };
+        // Kept to what a browser allows, so that the shaders are checked against it
+        let limits = wgpu::Limits::downlevel_webgl2_defaults();
+        let (device, queue) = request_device(&adapter, limits).await;
+
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("target_texture"),
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let target = Target::Texture(texture);
+        Some(Self::with_target(device, queue, format, size, target))
+    }
+
+    fn with_target(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+        target: Target,
+    ) -> Self {
+        let num_vertices = 0;
+        let line_num_vertices = 0;
+        let aspect_ratio = size.height as f32 / size.width as f32;
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("wgsl/shader.wgsl").into()),
         });
 
+        let atlas = Atlas::generate();
+        let atlas_size = wgpu::Extent3d {
+            width: atlas.width,
+            height: atlas.height,
+            depth_or_array_layers: 1,
+        };
+        let atlas_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("atlas_texture"),
+            size: atlas_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &atlas_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &atlas.pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * atlas.width),
+                rows_per_image: Some(atlas.height),
+            },
+            atlas_size,
+        );
+
+        let atlas_view = atlas_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let atlas_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let atlas_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("atlas_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        let atlas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("atlas_bind_group"),
+            layout: &atlas_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&atlas_sampler),
+                },
+            ],
+        });
+
+        let fog_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("fog_buffer"),
+            contents: bytemuck::cast_slice(&[FogUniform::from(Fog::none())]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let fog_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("fog_bind_group_layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let fog_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("fog_bind_group"),
+            layout: &fog_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: fog_buffer.as_entire_binding(),
+            }],
+        });
+
+        let raycast_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("raycast_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Uint,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let view_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("view_buffer"),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            size: std::mem::size_of::<ViewUniform>() as u64,
+            mapped_at_creation: false,
+        });
+        let map_texture = create_map_texture(&device, [1, 1]);
+        let light_texture = create_light_texture(&device, [1, 1]);
+        let raycast_bind_group = create_raycast_bind_group(
+            &device,
+            &raycast_bind_group_layout,
+            &map_texture,
+            &light_texture,
+            &view_buffer,
+        );
+
+        let raycast_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Raycast Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("wgsl/raycast.wgsl").into()),
+        });
+
+        let raycast_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Raycast Pipeline Layout"),
+                bind_group_layouts: &[
+                    &atlas_bind_group_layout,
+                    &fog_bind_group_layout,
+                    &raycast_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let raycast_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Raycast Pipeline"),
+            layout: Some(&raycast_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &raycast_shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &raycast_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[],
+                bind_group_layouts: &[&atlas_bind_group_layout, &fog_bind_group_layout],
                 push_constant_ranges: &[],
             });
 
@@ -105,8 +396,8 @@ impl WGPUState {
                 module: &shader,
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
             }),
@@ -140,7 +431,7 @@ impl WGPUState {
                 module: &shader,
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
+                    format,
                     blend: Some(wgpu::BlendState::REPLACE),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
@@ -166,32 +457,30 @@ impl WGPUState {
         let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("vertex_buffer"),
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            size: std::mem::size_of::<Vertex>() as u64 * MAX_VERTICES,
+            size: std::mem::size_of::<Vertex>() as u64 * INITIAL_VERTICES,
             mapped_at_creation: false,
         });
 
         let line_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("line_vertex_buffer"),
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            size: std::mem::size_of::<Vertex>() as u64 * MAX_VERTICES,
+            size: std::mem::size_of::<Vertex>() as u64 * INITIAL_VERTICES,
             mapped_at_creation: false,
         });
 
         let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("index_buffer"),
             usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-            size: std::mem::size_of::<u16>() as u64 * MAX_INDICES,
+            size: std::mem::size_of::<u32>() as u64 * INITIAL_INDICES,
             mapped_at_creation: false,
         });
         let num_indices = 0;
 
         Self {
             aspect_ratio,
-            window,
-            surface,
+            target,
             device,
             queue,
-            config,
             size,
             render_pipeline,
             line_render_pipeline,
@@ -199,49 +488,178 @@ impl WGPUState {
             tri_num_vertices: num_vertices,
             tri_index_buffer: index_buffer,
             tri_num_indices: num_indices,
+            atlas_bind_group,
+            fog_buffer,
+            fog_bind_group,
+            raycast_pipeline,
+            raycast_bind_group_layout,
+            raycast_bind_group,
+            map_texture,
+            light_texture,
+            view_buffer,
+            raycast: false,
             line_vertex_buffer,
             line_num_vertices,
         }
     }
 
     pub fn window(&self) -> &Window {
-        &self.window
+        match &self.target {
+            Target::Window { window, .. } => window,
+            #[cfg(test)]
+            Target::Texture(_) => panic!("offscreen frames have no window"),
+        }
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        let (surface, config) = match &mut self.target {
+            Target::Window {
+                surface, config, ..
+            } => (surface, config),
+            // Offscreen frames stay the size they were made
+            #[cfg(test)]
+            Target::Texture(_) => return,
+        };
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
+            config.width = new_size.width;
+            config.height = new_size.height;
             self.aspect_ratio = new_size.height as f32 / new_size.width as f32;
-            self.surface.configure(&self.device, &self.config);
+            surface.configure(&self.device, config);
         }
     }
 
-    pub fn update_line(&mut self, vertices: &[Vertex]) {
-        self.line_num_vertices = vertices.len() as u32;
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.queue.write_buffer(
+            &self.fog_buffer,
+            0,
+            bytemuck::cast_slice(&[FogUniform::from(fog)]),
+        );
+    }
+
+    /// How many texels across the largest texture the device can hold is.
+    pub fn max_texture_size(&self) -> u32 {
+        self.device.limits().max_texture_dimension_2d
+    }
+
+    /// Has the 3D view raycast on the GPU from `frame`, or leaves it to the
+    /// CPU built geometry when None.
+    pub fn set_raycast(&mut self, frame: Option<&RaycastFrame>) {
+        self.raycast = frame.is_some();
+        let Some(frame) = frame else {
+            return;
+        };
 
+        let [width, height] = frame.size;
+        if texture_size(&self.map_texture) != frame.size {
+            self.map_texture = create_map_texture(&self.device, frame.size);
+            self.update_raycast_bind_group();
+        }
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.map_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&frame.texels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(16 * width),
+                rows_per_image: Some(height),
+            },
+            self.map_texture.size(),
+        );
         self.queue
-            .write_buffer(&self.line_vertex_buffer, 0, bytemuck::cast_slice(vertices));
+            .write_buffer(&self.view_buffer, 0, bytemuck::cast_slice(&[frame.view]));
     }
 
-    pub fn update_tri(&mut self, vertices: &[Vertex], indices: &[u16]) {
+    /// Gives the GPU raycaster the light on every cell, which it keeps until
+    /// it is given new light.
+    pub fn set_raycast_lights(&mut self, lights: &RaycastLights) {
+        let [width, height] = lights.size;
+        if texture_size(&self.light_texture) != lights.size {
+            self.light_texture = create_light_texture(&self.device, lights.size);
+            self.update_raycast_bind_group();
+        }
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.light_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&lights.lights),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            self.light_texture.size(),
+        );
+    }
+
+    /// Binds the raycaster's textures again after either is replaced.
+    fn update_raycast_bind_group(&mut self) {
+        self.raycast_bind_group = create_raycast_bind_group(
+            &self.device,
+            &self.raycast_bind_group_layout,
+            &self.map_texture,
+            &self.light_texture,
+            &self.view_buffer,
+        );
+    }
+
+    pub fn update_line(&mut self, vertices: &[Vertex]) {
+        self.line_num_vertices = vertices.len() as u32;
+        let data = bytemuck::cast_slice(vertices);
+        reserve(
+            &self.device,
+            &mut self.line_vertex_buffer,
+            data.len() as u64,
+        );
+
+        self.queue.write_buffer(&self.line_vertex_buffer, 0, data);
+    }
+
+    pub fn update_tri(&mut self, vertices: &[Vertex], indices: &[u32]) {
         self.tri_num_indices = indices.len() as u32;
         self.tri_num_vertices = vertices.len() as u32;
 
+        let vertex_data = bytemuck::cast_slice(vertices);
+        reserve(
+            &self.device,
+            &mut self.tri_vertex_buffer,
+            vertex_data.len() as u64,
+        );
         self.queue
-            .write_buffer(&self.tri_vertex_buffer, 0, bytemuck::cast_slice(vertices));
-
+            .write_buffer(&self.tri_vertex_buffer, 0, vertex_data);
+
+        let index_data = bytemuck::cast_slice(indices);
+        reserve(
+            &self.device,
+            &mut self.tri_index_buffer,
+            index_data.len() as u64,
+        );
         self.queue
-            .write_buffer(&self.tri_index_buffer, 0, bytemuck::cast_slice(indices));
+            .write_buffer(&self.tri_index_buffer, 0, index_data);
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let (output, view) = match &self.target {
+            Target::Window { surface, .. } => {
+                let output = surface.get_current_texture()?;
+                let view = output
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+                (Some(output), view)
+            }
+            #[cfg(test)]
+            Target::Texture(texture) => {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                (None, view)
+            }
+        };
 
         let mut encoder = self
             .device
@@ -268,10 +686,20 @@ impl WGPUState {
                 depth_stencil_attachment: None,
             });
 
+            if self.raycast {
+                render_pass.set_pipeline(&self.raycast_pipeline);
+                render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
+                render_pass.set_bind_group(1, &self.fog_bind_group, &[]);
+                render_pass.set_bind_group(2, &self.raycast_bind_group, &[]);
+                render_pass.draw(0..6, 0..1);
+            }
+
             render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.fog_bind_group, &[]);
             render_pass.set_vertex_buffer(0, self.tri_vertex_buffer.slice(..));
             render_pass
-                .set_index_buffer(self.tri_index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+                .set_index_buffer(self.tri_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
             render_pass.draw_indexed(0..self.tri_num_indices, 0, 0..1);
         }
 
@@ -290,12 +718,167 @@ impl WGPUState {
             });
 
             render_pass.set_pipeline(&self.line_render_pipeline);
+            render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.fog_bind_group, &[]);
             render_pass.set_vertex_buffer(0, self.line_vertex_buffer.slice(..));
             render_pass.draw(0..self.line_num_vertices, 0..1);
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+        if let Some(output) = output {
+            output.present();
+        }
         Ok(())
     }
+
+    /// The pixels of the last frame drawn offscreen, row by row from the top,
+    /// four bytes each.
+    #[cfg(test)]
+    pub fn read_pixels(&self) -> Vec<u8> {
+        let Target::Texture(texture) = &self.target else {
+            panic!("only offscreen frames can be read back");
+        };
+        let (width, height) = (texture.width(), texture.height());
+        // Rows are copied out padded to the alignment the copy needs
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let row = 4 * width;
+        let padded_row = row.div_ceil(align) * align;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("read_buffer"),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            size: (padded_row * height) as u64,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Read Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        self.device.poll(wgpu::Maintain::Wait);
+        let data = slice.get_mapped_range();
+        data.chunks(padded_row as usize)
+            .flat_map(|padded| &padded[..row as usize])
+            .copied()
+            .collect()
+    }
+}
+
+async fn request_device(
+    adapter: &wgpu::Adapter,
+    limits: wgpu::Limits,
+) -> (wgpu::Device, wgpu::Queue) {
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::empty(),
+                limits,
+                label: None,
+            },
+            None,
+        )
+        .await
+        .unwrap()
+}
+
+/// A texture holding one texel of map data per cell, for the GPU raycaster.
+fn create_map_texture(device: &wgpu::Device, [width, height]: [u32; 2]) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("map_texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Uint,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn texture_size(texture: &wgpu::Texture) -> [u32; 2] {
+    [texture.width(), texture.height()]
+}
+
+/// A texture holding the light samples of every cell, for the GPU raycaster.
+fn create_light_texture(device: &wgpu::Device, [width, height]: [u32; 2]) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("light_texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_raycast_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    map_texture: &wgpu::Texture,
+    light_texture: &wgpu::Texture,
+    view_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    let map_view = map_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let light_view = light_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("raycast_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&map_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: view_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&light_view),
+            },
+        ],
+    })
+}
+
+/// Replaces `buffer` with one at least `size` bytes long if it is too small,
+/// doubling its size so that a growing scene doesn't reallocate every frame.
+fn reserve(device: &wgpu::Device, buffer: &mut wgpu::Buffer, size: u64) {
+    if size <= buffer.size() {
+        return;
+    }
+    let mut new_size = buffer.size().max(1);
+    while new_size < size {
+        new_size *= 2;
+    }
+    *buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("resized_buffer"),
+        usage: buffer.usage(),
+        size: new_size,
+        mapped_at_creation: false,
+    });
 }
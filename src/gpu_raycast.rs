@@ -0,0 +1,348 @@
+use crate::{
+    camera::Camera,
+    game::{
+        GROUND_COLOR, LIGHT_SAMPLES, LIT_TINT, MIRROR_BOUNCES, MIRROR_TINT, SKY_COLOR, WALL_HEIGHT,
+    },
+    light::{light_at, LightGrid},
+    map::GameMap,
+    ray::{Face, MAX_PORTALS},
+    sprite::Sprite,
+    texture::{face_shades, slot_count, tile_slot, ATLAS_COLUMNS, TILE_SLOTS},
+};
+
+/// How many storeys, and across all of them how many moving push walls,
+/// segment walls, sprites and faces with portals on, the GPU raycaster can
+/// draw. Frames with any more are left to the CPU.
+pub const MAX_STOREYS: usize = 4;
+pub const MAX_PUSH_WALLS: usize = 8;
+pub const MAX_SEGMENTS: usize = 32;
+pub const MAX_SPRITES: usize = 16;
+pub const MAX_PORTAL_FACES: usize = 16;
+
+/// What kind of cell a map texel holds, as far as rays are concerned.
+const EMPTY: u32 = 0;
+const WALL: u32 = 1;
+const SEE_THROUGH: u32 = 2;
+const DOOR_ALONG_X: u32 = 3;
+const DOOR_ALONG_Y: u32 = 4;
+const MIRROR: u32 = 5;
+
+/// Everything the GPU raycaster needs to draw one frame of the 3D view.
+pub struct RaycastFrame {
+    /// One texel per cell, row by row from the bottom of each storey's map,
+    /// with each storey's rows above the storey below's. Each holds the
+    /// cell's kind and wall texture slot, its floor and ceiling texture
+    /// slots, the bits of its wall height and the bits of its door's open amount.
+    pub texels: Vec<[u32; 4]>,
+    pub size: [u32; 2],
+    pub view: ViewUniform,
+}
+
+/// The light on every cell of a level for the GPU raycaster, which only
+/// changes when the maps do and so is built apart from the frames.
+pub struct RaycastLights {
+    /// A block of light samples per cell, in the same order as the frame's
+    /// texels. The floor samples fill the middle of each block and the
+    /// samples along each face the edge on that side.
+    pub lights: Vec<[u8; 4]>,
+    pub size: [u32; 2],
+}
+
+impl RaycastFrame {
+    /// The frame seen by `camera` on storey `storey` of a level, with each
+    /// sprite drawn in the colour it is lit. Returns None if there are more
+    /// storeys, push walls, segment walls, sprites or portals than the shader
+    /// can hold, or if the level's map or light texture would be more than
+    /// `max_texture_size` texels across.
+    pub fn new(
+        storeys: &[GameMap],
+        storey: usize,
+        camera: &Camera,
+        sprites: &[(Sprite, [f32; 3])],
+        columns: usize,
+        max_distance: f32,
+        max_texture_size: u32,
+    ) -> Option<Self> {
+        let push_wall_count: usize = storeys.iter().map(|map| map.push_walls().len()).sum();
+        let segment_count: usize = storeys.iter().map(|map| map.segments().len()).sum();
+        // Portals to storeys that are not there are walls, as on the CPU
+        let portal_faces: Vec<_> = storeys
+            .iter()
+            .enumerate()
+            .flat_map(|(index, map)| map.portals().map(move |portal| (index, portal)))
+            .filter(|(_, (_, _, portal))| portal.storey < storeys.len())
+            .collect();
+        let width = storeys.iter().map(GameMap::width).max().unwrap_or(1);
+        let height: usize = storeys.iter().map(GameMap::height).sum();
+        // The light texture holds a block of samples for each map texel
+        let light_size = width.max(height) * (LIGHT_SAMPLES + 2);
+        if storeys.len() > MAX_STOREYS
+            || push_wall_count > MAX_PUSH_WALLS
+            || segment_count > MAX_SEGMENTS
+            || sprites.len() > MAX_SPRITES
+            || portal_faces.len() > MAX_PORTAL_FACES
+            || light_size > max_texture_size as usize
+        {
+            return None;
+        }
+
+        let mut texels = Vec::with_capacity(width * height);
+        let mut storey_data = [[0.0; 4]; MAX_STOREYS * 2];
+        let mut push_walls = [[0.0; 4]; MAX_PUSH_WALLS * 2];
+        let mut segments = [[0.0; 4]; MAX_SEGMENTS * 2];
+        let (mut push_wall, mut segment) = (0, 0);
+        for (index, map) in storeys.iter().enumerate() {
+            let row = texels.len() / width;
+            storey_data[index * 2] = [
+                map.width() as f32,
+                map.height() as f32,
+                row as f32,
+                map.cell_width(),
+            ];
+            let offset = index as f32 - storey as f32;
+            // Lit cells are lit in pieces, each at its middle
+            let pieces = if map.lights().is_empty() {
+                1
+            } else {
+                LIGHT_SAMPLES
+            };
+            storey_data[index * 2 + 1] = [map.tallest_wall(), offset, pieces as f32, 0.0];
+
+            for y in 0..map.height() {
+                for x in 0..width {
+                    texels.push(if x < map.width() {
+                        texel(map, x, y)
+                    } else {
+                        [EMPTY; 4]
+                    });
+                }
+            }
+            for wall in map.push_walls() {
+                push_walls[push_wall * 2] = [
+                    wall.cell[0] as f32,
+                    wall.cell[1] as f32,
+                    wall.direction[0] as f32,
+                    wall.direction[1] as f32,
+                ];
+                push_walls[push_wall * 2 + 1] =
+                    [wall.offset, tile_slot(wall.tile) as f32, 0.0, index as f32];
+                push_wall += 1;
+            }
+            for wall in map.segments() {
+                let [start_x, start_y] = wall.start;
+                let [end_x, end_y] = wall.end;
+                segments[segment * 2] = [start_x, start_y, end_x, end_y];
+                segments[segment * 2 + 1] = [tile_slot(wall.tile) as f32, 0.0, 0.0, index as f32];
+                segment += 1;
+            }
+        }
+
+        let mut portals = [[0.0; 4]; MAX_PORTAL_FACES * 2];
+        for (i, (index, ([x, y], face, portal))) in portal_faces.iter().enumerate() {
+            let [to_x, to_y] = portal.cell;
+            portals[i * 2] = [*x as f32, *y as f32, face_index(*face), *index as f32];
+            portals[i * 2 + 1] = [
+                to_x as f32,
+                to_y as f32,
+                face_index(portal.face),
+                portal.storey as f32,
+            ];
+        }
+
+        let rgba = |[r, g, b]: [f32; 3]| [r, g, b, 1.0];
+        let mut sprite_data = [[0.0; 4]; MAX_SPRITES * 2];
+        for (i, (sprite, [r, g, b])) in sprites.iter().enumerate() {
+            let [x, y] = sprite.pos;
+            sprite_data[i * 2] = [x, y, sprite.texture as f32, sprite.scale];
+            sprite_data[i * 2 + 1] = [*r, *g, *b, sprite.storey as f32];
+        }
+
+        // Laid out by tile slot, then in the shader's face order
+        let mut shades = [[0.0; 4]; TILE_SLOTS as usize * 4];
+        for (slot, slot_shades) in shades.chunks_mut(4).enumerate() {
+            for (shade, face) in slot_shades.iter_mut().zip(FACES) {
+                *shade = rgba(face_shades(slot as u8).shade(face));
+            }
+        }
+        let view = ViewUniform {
+            position: camera.pos,
+            direction: camera.direction,
+            plane: camera.plane,
+            horizon: camera.horizon,
+            eye: camera.eye,
+            max_distance,
+            wall_height: WALL_HEIGHT * camera.zoom(),
+            columns: columns as f32,
+            storey_count: storeys.len() as u32,
+            ground: rgba(GROUND_COLOR),
+            sky: rgba(SKY_COLOR),
+            lit: rgba(LIT_TINT),
+            mirror_tint: rgba(MIRROR_TINT),
+            atlas: [
+                ATLAS_COLUMNS as f32,
+                slot_count().div_ceil(ATLAS_COLUMNS) as f32,
+                0.0,
+                0.0,
+            ],
+            storeys: storey_data,
+            push_walls,
+            segments,
+            sprites: sprite_data,
+            portals,
+            face_shades: shades,
+            push_wall_count: push_wall_count as u32,
+            segment_count: segment_count as u32,
+            sprite_count: sprites.len() as u32,
+            portal_count: portal_faces.len() as u32,
+            max_portals: MAX_PORTALS as u32,
+            max_bounces: MIRROR_BOUNCES as u32,
+            light_samples: LIGHT_SAMPLES as u32,
+            _padding: 0,
+        };
+
+        Some(Self {
+            texels,
+            size: [width as u32, height as u32],
+            view,
+        })
+    }
+}
+
+impl RaycastLights {
+    /// The light on every cell of a level, with the floors lit from each
+    /// storey's light grid and the faces of the walls as the lightmap lights them.
+    pub fn new(storeys: &[GameMap], light_grids: &[LightGrid]) -> Self {
+        let width = storeys.iter().map(GameMap::width).max().unwrap_or(1);
+        let height: usize = storeys.iter().map(GameMap::height).sum();
+        let block = LIGHT_SAMPLES + 2;
+        let light_width = width * block;
+        let mut lights = vec![[0; 4]; light_width * height * block];
+        let mut row = 0;
+        for (index, map) in storeys.iter().enumerate() {
+            let sample = |k: usize| (k as f32 + 0.5) / LIGHT_SAMPLES as f32;
+            for y in 0..map.height() {
+                for x in 0..map.width() {
+                    let mut set = |[u, v]: [usize; 2], light: [f32; 3]| {
+                        let [r, g, b] = light.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                        let i = ((row + y) * block + v) * light_width + x * block + u;
+                        lights[i] = [r, g, b, 255];
+                    };
+                    let [left, bottom] = [x as f32, y as f32];
+                    for v in 0..LIGHT_SAMPLES {
+                        for u in 0..LIGHT_SAMPLES {
+                            let point = [left + sample(u), bottom + sample(v)];
+                            set([u + 1, v + 1], light_grids[index].at(point));
+                        }
+                    }
+                    // Along each face from its low end, as the lightmap samples it
+                    for k in 0..LIGHT_SAMPLES {
+                        let [across_x, across_y] = [left + sample(k), bottom + sample(k)];
+                        let west = light_at(map, [left, across_y], Some(Face::West));
+                        let east = light_at(map, [left + 1.0, across_y], Some(Face::East));
+                        let south = light_at(map, [across_x, bottom], Some(Face::South));
+                        let north = light_at(map, [across_x, bottom + 1.0], Some(Face::North));
+                        set([0, k + 1], west);
+                        set([block - 1, k + 1], east);
+                        set([k + 1, 0], south);
+                        set([k + 1, block - 1], north);
+                    }
+                }
+            }
+            row += map.height();
+        }
+
+        Self {
+            lights,
+            size: [light_width as u32, (height * block) as u32],
+        }
+    }
+}
+
+/// The order the shader numbers faces in.
+const FACES: [Face; 4] = [Face::West, Face::East, Face::South, Face::North];
+
+fn face_index(face: Face) -> f32 {
+    FACES.iter().position(|&f| f == face).unwrap_or_default() as f32
+}
+
+/// The texel holding a cell of a map.
+fn texel(map: &GameMap, x: usize, y: usize) -> [u32; 4] {
+    let tile = map[(x, y)];
+    let kind = if map.is_door(x, y) {
+        if map.door_runs_along_x(x, y) {
+            DOOR_ALONG_X
+        } else {
+            DOOR_ALONG_Y
+        }
+    } else if map.is_see_through(x, y) {
+        SEE_THROUGH
+    } else if map.is_mirror(x, y) {
+        MIRROR
+    } else if tile != 0 {
+        WALL
+    } else {
+        EMPTY
+    };
+    [
+        kind | (tile_slot(tile) << 8),
+        tile_slot(map.floor(x, y)) | (tile_slot(map.ceiling(x, y)) << 16),
+        map.wall_height(x, y).to_bits(),
+        map.door(x, y).open.to_bits(),
+    ]
+}
+
+/// The camera and drawing settings laid out for the raycast shader's uniform buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ViewUniform {
+    /// Camera position in world space.
+    position: [f32; 2],
+    direction: [f32; 2],
+    plane: [f32; 2],
+    /// Height of the horizon on the screen, moved by looking up or down.
+    horizon: f32,
+    /// Height of the eye above the floor of the player's storey, in standard
+    /// wall heights.
+    eye: f32,
+    max_distance: f32,
+    wall_height: f32,
+    columns: f32,
+    storey_count: u32,
+    ground: [f32; 4],
+    sky: [f32; 4],
+    lit: [f32; 4],
+    /// What each bounce off a mirror tints anything seen in it by.
+    mirror_tint: [f32; 4],
+    /// Columns and rows of texture slots in the atlas.
+    atlas: [f32; 4],
+    /// Two entries per storey: its size in cells, the first row of the map
+    /// texture it takes up and its cell width, then its tallest wall, which
+    /// only walls as tall stop rays, how far its floor is above the player's
+    /// storey's and how many pieces its floors and ceilings are lit in.
+    storeys: [[f32; 4]; MAX_STOREYS * 2],
+    /// Two entries per moving push wall: its cell and direction, then how
+    /// far it has slid, its texture slot and its storey.
+    push_walls: [[f32; 4]; MAX_PUSH_WALLS * 2],
+    /// Two entries per segment wall: its start and end, then its texture slot
+    /// and its storey.
+    segments: [[f32; 4]; MAX_SEGMENTS * 2],
+    /// Two entries per sprite: its position, texture slot and scale, then
+    /// its colour and its storey.
+    sprites: [[f32; 4]; MAX_SPRITES * 2],
+    /// Two entries per face with a portal on: its cell, face and storey,
+    /// then the cell, face and storey the portal leads to.
+    portals: [[f32; 4]; MAX_PORTAL_FACES * 2],
+    /// The shade of each face of each tile texture slot.
+    face_shades: [[f32; 4]; TILE_SLOTS as usize * 4],
+    push_wall_count: u32,
+    segment_count: u32,
+    sprite_count: u32,
+    portal_count: u32,
+    /// How many portals a ray goes through and mirrors it bounces off before
+    /// it gives up.
+    max_portals: u32,
+    max_bounces: u32,
+    /// Light samples across each cell and along each of its faces.
+    light_samples: u32,
+    _padding: u32,
+}
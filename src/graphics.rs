@@ -1,6 +1,7 @@
 use crate::{
-    gpu::{WGPUState, MAX_INDICES, MAX_VERTICES},
+    gpu::{WGPUState, INITIAL_INDICES, INITIAL_VERTICES},
     ray::Ray,
+    texture::{atlas_uv, BLANK_SLOT},
     util::convert_range,
     vertex::Vertex,
 };
@@ -8,7 +9,7 @@ use crate::{
 pub struct Graphics {
     pub gpu_state: WGPUState,
     vertices: Vec<Vertex>,
-    indices: Vec<u16>,
+    indices: Vec<u32>,
     lines: Vec<Vertex>,
 }
 
@@ -57,6 +58,22 @@ impl Line {
 }
 
 impl Quad {
+    /// Texture coordinates covering a region of an atlas slot, with `u` running
+    /// left to right and `v` top to bottom.
+    pub fn texture(slot: u32, u: [f32; 2], v: [f32; 2]) -> Self {
+        Self {
+            tl: atlas_uv(slot, u[0], v[0]),
+            bl: atlas_uv(slot, u[0], v[1]),
+            br: atlas_uv(slot, u[1], v[1]),
+            tr: atlas_uv(slot, u[1], v[0]),
+        }
+    }
+
+    /// Texture coordinates for untextured geometry.
+    pub fn blank() -> Self {
+        Self::texture(BLANK_SLOT, [0.5, 0.5], [0.5, 0.5])
+    }
+
     pub fn translate(&mut self, x: f32, y: f32) {
         let t_point = |p: [f32; 2]| -> [f32; 2] { [p[0] + x, p[1] + y] };
 
@@ -85,9 +102,9 @@ impl Graphics {
     pub fn new(gpu_state: WGPUState) -> Self {
         Self {
             gpu_state,
-            vertices: Vec::with_capacity(MAX_VERTICES as usize),
-            lines: Vec::with_capacity(MAX_VERTICES as usize),
-            indices: Vec::with_capacity(MAX_INDICES as usize),
+            vertices: Vec::with_capacity(INITIAL_VERTICES as usize),
+            lines: Vec::with_capacity(INITIAL_VERTICES as usize),
+            indices: Vec::with_capacity(INITIAL_INDICES as usize),
         }
     }
 
@@ -97,8 +114,8 @@ impl Graphics {
         self.vertices.clear();
     }
 
-    fn offset(&self) -> u16 {
-        let length = self.vertices.len() as u16;
+    fn offset(&self) -> u32 {
+        let length = self.vertices.len() as u32;
         if length > 0 {
             length
         } else {
@@ -116,10 +133,19 @@ impl Graphics {
         self.push_rect(square, color);
     }
 
+    /// Draws a ray on the map panel, cut short where it leaves the panel.
     pub fn push_ray(&mut self, ray: Ray, color: [f32; 3]) {
+        let radians = ray.rotation.to_radians();
+        let direction = [-radians.sin(), radians.cos()];
+        let mut length = ray.length;
+        for (direction, origin) in direction.into_iter().zip(ray.origin) {
+            if direction != 0.0 {
+                length = length.min((direction.signum() - origin) / direction);
+            }
+        }
         let mut line = Line {
             start: [0.0, 0.0],
-            end: [0.0, 0.0 + ray.length],
+            end: [0.0, 0.0 + length.max(0.0)],
         };
 
         line.rotate(ray.rotation);
@@ -132,20 +158,26 @@ impl Graphics {
         line.start[0] = convert_range(line.start[0], [-1.0, 1.0], [-1.0, 0.0]);
         line.end[0] = convert_range(line.end[0], [-1.0, 1.0], [-1.0, 0.0]);
 
+        let tex_coords = atlas_uv(BLANK_SLOT, 0.5, 0.5);
         self.lines.extend_from_slice(&[
             Vertex {
                 position: [line.start[0], line.start[1]],
                 color,
+                tex_coords,
+                depth: 0.0,
             },
             Vertex {
                 position: [line.end[0], line.end[1]],
                 color,
+                tex_coords,
+                depth: 0.0,
             },
         ]);
     }
 
-    fn push_quad(&mut self, mut quad: Quad, color: [f32; 3]) {
+    fn push_quad(&mut self, mut quad: Quad, color: [f32; 3], tex_coords: Quad) {
         let offset = self.offset();
+        let depth = [0.0; 4];
 
         quad.tl[0] = convert_range(quad.tl[0], [-1.0, 1.0], [-1.0, 0.0]);
         quad.tr[0] = convert_range(quad.tr[0], [-1.0, 1.0], [-1.0, 0.0]);
@@ -156,32 +188,44 @@ impl Graphics {
             Vertex {
                 position: [quad.tl[0], quad.tl[1]],
                 color,
+                tex_coords: tex_coords.tl,
+                depth: depth[0],
             },
             Vertex {
                 position: [quad.bl[0], quad.bl[1]],
                 color,
+                tex_coords: tex_coords.bl,
+                depth: depth[1],
             },
             Vertex {
                 position: [quad.br[0], quad.br[1]],
                 color,
+                tex_coords: tex_coords.br,
+                depth: depth[2],
             },
             Vertex {
                 position: [quad.tr[0], quad.tr[1]],
                 color,
+                tex_coords: tex_coords.tr,
+                depth: depth[3],
             },
         ]);
 
         self.indices.extend_from_slice(&[
-            0 + offset,
+            offset,
             1 + offset,
             2 + offset,
             3 + offset,
-            0 + offset,
+            offset,
             2 + offset,
         ]);
     }
 
     pub fn push_rect_right(&mut self, rect: Rect, color: [f32; 3]) {
+        self.push_textured_rect_right(rect, color, Quad::blank());
+    }
+
+    pub fn push_textured_rect_right(&mut self, rect: Rect, color: [f32; 3], tex_coords: Quad) {
         let hw = rect.width / 2.0;
         let hh = rect.height / 2.0;
 
@@ -194,10 +238,18 @@ impl Graphics {
 
         quad.rotate(rect.rotation);
         quad.translate(rect.origin[0], rect.origin[1]);
-        self.push_quad_right(quad, color);
+        self.push_textured_quad_right(quad, color, tex_coords, [0.0; 4]);
     }
 
-    fn push_quad_right(&mut self, mut quad: Quad, color: [f32; 3]) {
+    /// Pushes a quad to the right half of the screen, with the distance of
+    /// each corner from the camera given in `tl`, `bl`, `br`, `tr` order.
+    pub fn push_textured_quad_right(
+        &mut self,
+        mut quad: Quad,
+        color: [f32; 3],
+        tex_coords: Quad,
+        depth: [f32; 4],
+    ) {
         let offset = self.offset();
 
         quad.tl[0] = convert_range(quad.tl[0], [-1.0, 1.0], [0.0, 1.0]);
@@ -209,31 +261,43 @@ impl Graphics {
             Vertex {
                 position: [quad.tl[0], quad.tl[1]],
                 color,
+                tex_coords: tex_coords.tl,
+                depth: depth[0],
             },
             Vertex {
                 position: [quad.bl[0], quad.bl[1]],
                 color,
+                tex_coords: tex_coords.bl,
+                depth: depth[1],
             },
             Vertex {
                 position: [quad.br[0], quad.br[1]],
                 color,
+                tex_coords: tex_coords.br,
+                depth: depth[2],
             },
             Vertex {
                 position: [quad.tr[0], quad.tr[1]],
                 color,
+                tex_coords: tex_coords.tr,
+                depth: depth[3],
             },
         ]);
 
         self.indices.extend_from_slice(&[
-            0 + offset,
+            offset,
             1 + offset,
             2 + offset,
             3 + offset,
-            0 + offset,
+            offset,
             2 + offset,
         ]);
     }
     pub fn push_rect(&mut self, rect: Rect, color: [f32; 3]) {
+        self.push_textured_rect(rect, color, Quad::blank());
+    }
+
+    pub fn push_textured_rect(&mut self, rect: Rect, color: [f32; 3], tex_coords: Quad) {
         let hw = rect.width / 2.0;
         let hh = rect.height / 2.0;
 
@@ -246,7 +310,7 @@ impl Graphics {
 
         quad.rotate(rect.rotation);
         quad.translate(rect.origin[0], rect.origin[1]);
-        self.push_quad(quad, color);
+        self.push_quad(quad, color, tex_coords);
     }
 
     pub fn draw(&mut self) -> Result<(), wgpu::SurfaceError> {
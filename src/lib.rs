@@ -1,13 +1,21 @@
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
+mod camera;
+pub mod fog;
 mod game;
 mod gpu;
+mod gpu_raycast;
 mod graphics;
+pub mod light;
+pub mod map;
 mod player;
-mod ray;
+pub mod ray;
+mod sprite;
+mod texture;
 mod util;
 mod vertex;
+pub mod visibility;
 
 use gpu::WGPUState;
 
@@ -77,18 +85,16 @@ pub async fn run() {
         Event::WindowEvent {
             ref event,
             window_id,
-        } if window_id == game.graphics.gpu_state.window().id() => {
-            if !game.input(event) {
-                match event {
-                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                    WindowEvent::Resized(physical_size) => {
-                        game.graphics.gpu_state.resize(*physical_size);
-                    }
-                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                        game.graphics.gpu_state.resize(**new_inner_size);
-                    }
-                    _ => {}
+        } if window_id == game.graphics.gpu_state.window().id() && !game.input(event) => {
+            match event {
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(physical_size) => {
+                    game.graphics.gpu_state.resize(*physical_size);
                 }
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    game.graphics.gpu_state.resize(**new_inner_size);
+                }
+                _ => {}
             }
         }
         _ => {}
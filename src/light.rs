@@ -0,0 +1,334 @@
+use crate::map::GameMap;
+use crate::ray::Face;
+use crate::visibility::line_of_sight;
+
+/// How far a lit point on a wall is moved off its face before checking
+/// whether a light reaches it, in cells, so that the wall itself does not
+/// shadow it.
+const SURFACE_OFFSET: f32 = 1e-3;
+
+/// A light shining equally in every direction from a point, fading out to
+/// nothing at its radius and blocked by anything that stops a ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    /// Position in map space, where a cell is one unit across.
+    pub pos: [f32; 2],
+    pub color: [f32; 3],
+    /// How many cells away the light reaches.
+    pub radius: f32,
+    /// Brightness right next to the light.
+    pub intensity: f32,
+    /// Whether the light is worked out every frame. Other lights are baked
+    /// into the map's lightmap once it has one.
+    pub dynamic: bool,
+}
+
+impl Light {
+    pub fn new(pos: [f32; 2], color: [f32; 3], radius: f32, intensity: f32) -> Self {
+        Self {
+            pos,
+            color,
+            radius,
+            intensity,
+            dynamic: false,
+        }
+    }
+
+    /// The light falling on a point in map space, or nothing if the point is
+    /// out of reach or in shadow. A point on a wall is given the face it is on,
+    /// so that only the side of the wall the light is on is lit.
+    pub fn reach(&self, map: &GameMap, point: [f32; 2], face: Option<Face>) -> [f32; 3] {
+        let point = match face {
+            Some(face) => {
+                let [x, y] = face.normal();
+                [point[0] + x * SURFACE_OFFSET, point[1] + y * SURFACE_OFFSET]
+            }
+            None => point,
+        };
+        let distance = (point[0] - self.pos[0]).hypot(point[1] - self.pos[1]);
+        if distance >= self.radius {
+            return [0.0; 3];
+        }
+        let from = map.to_world_space(self.pos);
+        if !line_of_sight(map, from, map.to_world_space(point)) {
+            return [0.0; 3];
+        }
+        let falloff = (1.0 - distance / self.radius).powi(2) * self.intensity;
+        self.color.map(|c| c * falloff)
+    }
+}
+
+/// The total light falling on a point in map space. Where the map has a
+/// lightmap this is the baked light plus whatever reaches the point from the
+/// dynamic lights, and otherwise the ambient light plus every light.
+pub fn light_at(map: &GameMap, point: [f32; 2], face: Option<Face>) -> [f32; 3] {
+    match map.lightmap() {
+        Some(lightmap) => {
+            let baked = lightmap.at(point, face);
+            let dynamic = map.lights().iter().filter(|light| light.dynamic);
+            add_lights(map, baked, dynamic, point, face)
+        }
+        None => add_lights(map, map.ambient(), map.lights(), point, face),
+    }
+}
+
+/// The light that is baked into a lightmap: the ambient light plus whatever
+/// reaches the point from the lights that are not dynamic.
+fn static_light_at(map: &GameMap, point: [f32; 2], face: Option<Face>) -> [f32; 3] {
+    let lights = map.lights().iter().filter(|light| !light.dynamic);
+    add_lights(map, map.ambient(), lights, point, face)
+}
+
+fn add_lights<'a>(
+    map: &GameMap,
+    base: [f32; 3],
+    lights: impl IntoIterator<Item = &'a Light>,
+    point: [f32; 2],
+    face: Option<Face>,
+) -> [f32; 3] {
+    lights
+        .into_iter()
+        .fold(base, |total, light| {
+            let [r, g, b] = light.reach(map, point, face);
+            [total[0] + r, total[1] + g, total[2] + b]
+        })
+        .map(|c| c.min(1.0))
+}
+
+/// Light baked from a map's static lights, stored with the map so that it
+/// only has to be traced once. Each cell has `samples` by `samples` points
+/// of floor light and `samples` points along each of its four faces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lightmap {
+    samples: usize,
+    width: usize,
+    height: usize,
+    /// Floor light in rows of cells, each cell's samples in rows.
+    floors: Vec<[f32; 3]>,
+    /// Face light of each cell in turn, its faces in `FACES` order, each
+    /// sampled from its low x or y end.
+    faces: Vec<[f32; 3]>,
+}
+
+const FACES: [Face; 4] = [Face::North, Face::South, Face::East, Face::West];
+
+impl Lightmap {
+    /// Traces the ambient and static lights of a map at every sample.
+    pub fn bake(map: &GameMap, samples: usize) -> Self {
+        assert!(samples > 0, "a lightmap needs at least one sample per cell");
+        let (width, height) = (map.width(), map.height());
+        let step = 1.0 / samples as f32;
+        let offset = |k: usize| (k as f32 + 0.5) * step;
+
+        let mut floors = Vec::with_capacity(width * height * samples * samples);
+        let mut faces = Vec::with_capacity(width * height * FACES.len() * samples);
+        for j in 0..height {
+            for i in 0..width {
+                for v in 0..samples {
+                    for u in 0..samples {
+                        let point = [i as f32 + offset(u), j as f32 + offset(v)];
+                        floors.push(static_light_at(map, point, None));
+                    }
+                }
+                for face in FACES {
+                    let [nx, ny] = face.normal();
+                    for k in 0..samples {
+                        // Across the face from its low end, on the cell's edge
+                        let point = if nx != 0.0 {
+                            [i as f32 + 0.5 + nx / 2.0, j as f32 + offset(k)]
+                        } else {
+                            [i as f32 + offset(k), j as f32 + 0.5 + ny / 2.0]
+                        };
+                        faces.push(static_light_at(map, point, Some(face)));
+                    }
+                }
+            }
+        }
+        Self {
+            samples,
+            width,
+            height,
+            floors,
+            faces,
+        }
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The baked light at a point in map space. A point on a face of its
+    /// cell's edge reads that face's samples. Points on doors, push walls and
+    /// segment walls, which do not lie on the grid, read the floor just in
+    /// front of them instead.
+    pub fn at(&self, point: [f32; 2], face: Option<Face>) -> [f32; 3] {
+        let Some(face) = face else {
+            return self.floor(point);
+        };
+        let [nx, ny] = face.normal();
+        // Step back into the cell whose face this is, or out in front of a
+        // face that is not on the grid
+        let inside = [
+            point[0] - nx * SURFACE_OFFSET,
+            point[1] - ny * SURFACE_OFFSET,
+        ];
+        let outside = [
+            point[0] + nx * SURFACE_OFFSET,
+            point[1] + ny * SURFACE_OFFSET,
+        ];
+        let [i, j] = [
+            self.cell(inside[0], self.width),
+            self.cell(inside[1], self.height),
+        ];
+        let (edge, across) = if nx != 0.0 {
+            (point[0] - (i as f32 + 0.5 + nx / 2.0), point[1] - j as f32)
+        } else {
+            (point[1] - (j as f32 + 0.5 + ny / 2.0), point[0] - i as f32)
+        };
+        if edge.abs() > SURFACE_OFFSET {
+            return self.floor(outside);
+        }
+        let index = FACES.iter().position(|&f| f == face).unwrap();
+        let k = self.sample(across);
+        self.faces[((j * self.width + i) * FACES.len() + index) * self.samples + k]
+    }
+
+    fn floor(&self, [x, y]: [f32; 2]) -> [f32; 3] {
+        let [i, j] = [self.cell(x, self.width), self.cell(y, self.height)];
+        let [u, v] = [self.sample(x - i as f32), self.sample(y - j as f32)];
+        let cell = (j * self.width + i) * self.samples * self.samples;
+        self.floors[cell + v * self.samples + u]
+    }
+
+    /// The cell along one axis containing a coordinate, clamped to the map.
+    fn cell(&self, v: f32, size: usize) -> usize {
+        (v.max(0.0) as usize).min(size - 1)
+    }
+
+    /// The sample containing a position across a cell, from 0.0 to 1.0.
+    fn sample(&self, v: f32) -> usize {
+        ((v * self.samples as f32).max(0.0) as usize).min(self.samples - 1)
+    }
+}
+
+/// The light on the floor and ceiling of a map, sampled on a grid of
+/// `samples` by `samples` points in each cell so that shadows can be looked
+/// up rather than traced for every pixel.
+pub struct LightGrid {
+    samples: usize,
+    /// Width of the grid in samples.
+    width: usize,
+    light: Vec<[f32; 3]>,
+}
+
+impl LightGrid {
+    pub fn new(map: &GameMap, samples: usize) -> Self {
+        let width = map.width() * samples;
+        let height = map.height() * samples;
+        let step = 1.0 / samples as f32;
+        let light = (0..width * height)
+            .map(|i| {
+                let point = [
+                    ((i % width) as f32 + 0.5) * step,
+                    ((i / width) as f32 + 0.5) * step,
+                ];
+                light_at(map, point, None)
+            })
+            .collect();
+        Self {
+            samples,
+            width,
+            light,
+        }
+    }
+
+    /// The light at the sample nearest to a point in map space.
+    pub fn at(&self, [x, y]: [f32; 2]) -> [f32; 3] {
+        let height = self.light.len() / self.width;
+        let to_sample =
+            |v: f32, size: usize| ((v * self.samples as f32).max(0.0) as usize).min(size - 1);
+        self.light[to_sample(y, height) * self.width + to_sample(x, self.width)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMBIENT: [f32; 3] = [0.2; 3];
+
+    /// A bordered map lit by a white lamp near its east wall.
+    fn lit_map() -> GameMap {
+        let mut map = GameMap::bordered(10, 10);
+        map.set_ambient(AMBIENT);
+        map.add_light(Light::new([7.5, 4.5], [1.0; 3], 3.0, 1.0));
+        map
+    }
+
+    #[test]
+    fn floor_lookups_read_the_sample_the_point_is_in() {
+        let mut map = lit_map();
+        map.bake_lightmap(2);
+        let lightmap = map.lightmap().unwrap();
+        // Samples sit a quarter of a cell in from each side
+        assert_eq!(
+            lightmap.at([6.1, 4.9], None),
+            static_light_at(&map, [6.25, 4.75], None)
+        );
+        assert_eq!(lightmap.at([2.5, 2.5], None), AMBIENT);
+    }
+
+    #[test]
+    fn face_lookups_read_the_face_the_point_is_on() {
+        let mut map = lit_map();
+        map.bake_lightmap(2);
+        let lightmap = map.lightmap().unwrap();
+        let lit = static_light_at(&map, [9.0, 4.25], Some(Face::West));
+        assert_eq!(lightmap.at([9.0, 4.4], Some(Face::West)), lit);
+        assert!(lit[0] > AMBIENT[0]);
+        // The far side of the wall faces away from the light
+        assert_eq!(lightmap.at([10.0, 4.4], Some(Face::East)), AMBIENT);
+    }
+
+    #[test]
+    fn faces_off_the_grid_read_the_floor_in_front() {
+        let mut map = lit_map();
+        map.bake_lightmap(2);
+        let lightmap = map.lightmap().unwrap();
+        assert_eq!(
+            lightmap.at([7.2, 5.5], Some(Face::South)),
+            lightmap.at([7.2, 5.4], None)
+        );
+    }
+
+    #[test]
+    fn walls_shadow_the_floor_behind_them_beside_a_taller_wall() {
+        let mut map = lit_map();
+        map[(6, 4)] = 1;
+        map[(2, 8)] = 1;
+        map.set_wall_height(2, 8, 2.0);
+        assert_eq!(light_at(&map, [5.5, 4.5], None), AMBIENT);
+        // Past the wall's corner the light still gets through
+        assert!(light_at(&map, [6.5, 2.5], None)[0] > AMBIENT[0]);
+    }
+
+    #[test]
+    fn dynamic_lights_are_added_to_the_baked_light() {
+        let mut map = lit_map();
+        map.bake_lightmap(2);
+        let baked = map.lightmap().unwrap().at([2.5, 2.5], None);
+        assert_eq!(light_at(&map, [2.5, 2.5], None), baked);
+
+        let lamp = Light {
+            dynamic: true,
+            ..Light::new([2.5, 3.5], [0.5; 3], 2.0, 1.0)
+        };
+        map.add_light(lamp);
+        let [r, g, b] = lamp.reach(&map, [2.5, 2.5], None);
+        assert!(r > 0.0);
+        assert_eq!(
+            light_at(&map, [2.5, 2.5], None),
+            [baked[0] + r, baked[1] + g, baked[2] + b]
+        );
+    }
+}
@@ -0,0 +1,724 @@
+use std::{
+    collections::HashMap,
+    ops::{Index, IndexMut},
+};
+
+use crate::{
+    fog::Fog,
+    game::COORD_SIZE,
+    light::{Light, Lightmap},
+    ray::Face,
+    util::convert_range,
+};
+
+/// A door sitting across the middle of its cell, which slides sideways into
+/// the wall when opened.
+pub const DOOR_TILE: u8 = 10;
+
+/// A wall that looks like any other but slides away when pushed.
+pub const PUSH_WALL_TILE: u8 = 11;
+
+/// Tiles that block movement but can be seen through in places, so rays
+/// carry on past them.
+pub const WINDOW_TILE: u8 = 12;
+pub const GRATE_TILE: u8 = 13;
+pub const FENCE_TILE: u8 = 14;
+
+/// A wall that reflects rays off its faces.
+pub const MIRROR_TILE: u8 = 15;
+
+/// How many cells a push wall slides before coming to rest, unless it is
+/// stopped early by something in its way.
+pub const PUSH_WALL_DISTANCE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorState {
+    Closed,
+    Opening,
+    Open,
+    Closing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Door {
+    pub state: DoorState,
+    /// How far the door has slid open, from 0.0 when closed to 1.0 when open.
+    pub open: f32,
+}
+
+impl Default for Door {
+    fn default() -> Self {
+        Self {
+            state: DoorState::Closed,
+            open: 0.0,
+        }
+    }
+}
+
+/// Stairs or a lift in an open cell, carrying anyone who steps into the cell
+/// to the same cell of the storey above or below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lift {
+    Up,
+    Down,
+}
+
+/// Where a portal on a wall face leads: the face of a cell on the same or
+/// another storey, out of which anything going into the portal comes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Portal {
+    /// Index of the storey the portal leads to.
+    pub storey: usize,
+    pub cell: [usize; 2],
+    pub face: Face,
+}
+
+impl Portal {
+    /// Degrees anticlockwise that anything going into the portal through
+    /// `from` turns by, so that it comes out heading away from the far face.
+    pub fn turn(&self, from: Face) -> f32 {
+        (self.face.angle() - from.angle() - 180.0).rem_euclid(360.0)
+    }
+
+    /// Carries a map space point on `face` of `cell` through the portal to
+    /// the matching point on the face it leads to.
+    pub fn carry(&self, cell: [usize; 2], face: Face, point: [f32; 2]) -> [f32; 2] {
+        let centre = |cell: [usize; 2], face: Face| {
+            let [x, y] = face.normal();
+            [
+                cell[0] as f32 + 0.5 + x / 2.0,
+                cell[1] as f32 + 0.5 + y / 2.0,
+            ]
+        };
+        let from = centre(cell, face);
+        let to = centre(self.cell, self.face);
+        let [x, y] = [point[0] - from[0], point[1] - from[1]];
+        // Quarter turns are done exactly, so that the point stays on the face
+        let [x, y] = match self.turn(face) {
+            90.0 => [-y, x],
+            180.0 => [-x, -y],
+            270.0 => [y, -x],
+            _ => [x, y],
+        };
+        [to[0] + x, to[1] + y]
+    }
+}
+
+/// A push wall on the move. While moving it is not part of the map's tiles,
+/// and is written back into them once it comes to rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PushWall {
+    /// The cell the wall is sliding out of.
+    pub cell: [usize; 2],
+    pub direction: [isize; 2],
+    /// How far the wall has slid towards the next cell, from 0.0 to 1.0.
+    pub offset: f32,
+    /// Cells left to slide, including the one it is sliding into.
+    pub remaining: usize,
+    pub tile: u8,
+}
+
+impl PushWall {
+    /// The cell the wall is sliding into.
+    pub fn next_cell(&self) -> [usize; 2] {
+        [
+            self.cell[0].wrapping_add_signed(self.direction[0]),
+            self.cell[1].wrapping_add_signed(self.direction[1]),
+        ]
+    }
+
+    /// Whether any part of the wall is inside a cell.
+    pub fn occupies(&self, x: usize, y: usize) -> bool {
+        self.cell == [x, y] || self.next_cell() == [x, y]
+    }
+
+    /// Lower and upper corners of the wall in map coordinates.
+    pub fn bounds(&self) -> ([f32; 2], [f32; 2]) {
+        let min = [
+            self.cell[0] as f32 + self.direction[0] as f32 * self.offset,
+            self.cell[1] as f32 + self.direction[1] as f32 * self.offset,
+        ];
+        (min, [min[0] + 1.0, min[1] + 1.0])
+    }
+}
+
+/// A free-standing wall between two points in map coordinates, which need not
+/// line up with the grid. Segments are solid from both sides.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+    pub tile: u8,
+}
+
+impl Segment {
+    pub fn new(start: [f32; 2], end: [f32; 2], tile: u8) -> Self {
+        Self { start, end, tile }
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.end[0] - self.start[0]).hypot(self.end[1] - self.start[1])
+    }
+
+    /// Distance from a point to the nearest point on the segment.
+    pub fn distance_to(&self, point: [f32; 2]) -> f32 {
+        let along = [self.end[0] - self.start[0], self.end[1] - self.start[1]];
+        let rel = [point[0] - self.start[0], point[1] - self.start[1]];
+        let length_squared = along[0] * along[0] + along[1] * along[1];
+        let t = if length_squared > 0.0 {
+            ((rel[0] * along[0] + rel[1] * along[1]) / length_squared).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (rel[0] - along[0] * t).hypot(rel[1] - along[1] * t)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameMap {
+    width: usize,
+    height: usize,
+    tiles: Vec<u8>,
+    /// Floor and ceiling textures of each cell, 0 leaving the ground and sky showing.
+    floors: Vec<u8>,
+    ceilings: Vec<u8>,
+    /// How tall each cell's wall is, in standard wall heights.
+    heights: Vec<f32>,
+    /// The tallest of `heights`, and never below a standard wall.
+    tallest: f32,
+    doors: HashMap<[usize; 2], Door>,
+    lifts: HashMap<[usize; 2], Lift>,
+    portals: HashMap<([usize; 2], Face), Portal>,
+    push_walls: Vec<PushWall>,
+    segments: Vec<Segment>,
+    lights: Vec<Light>,
+    /// Light that reaches everywhere, whether or not any of the lights do.
+    ambient: [f32; 3],
+    /// Light baked from the ambient light and the static lights, if the map
+    /// has been baked.
+    lightmap: Option<Lightmap>,
+    fog: Fog,
+}
+
+impl GameMap {
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(width > 0 && height > 0, "map must have at least one cell");
+        Self {
+            width,
+            height,
+            tiles: vec![0; width * height],
+            floors: vec![0; width * height],
+            ceilings: vec![0; width * height],
+            heights: vec![1.0; width * height],
+            tallest: 1.0,
+            doors: HashMap::new(),
+            lifts: HashMap::new(),
+            portals: HashMap::new(),
+            push_walls: vec![],
+            segments: vec![],
+            lights: vec![],
+            ambient: [1.0; 3],
+            lightmap: None,
+            fog: Fog::none(),
+        }
+    }
+
+    /// An empty map enclosed by a one cell thick wall.
+    pub fn bordered(width: usize, height: usize) -> Self {
+        let mut map = Self::new(width, height);
+        for x in 0..width {
+            map[(x, 0)] = 1;
+            map[(x, height - 1)] = 1;
+        }
+        for y in 0..height {
+            map[(0, y)] = 1;
+            map[(width - 1, y)] = 1;
+        }
+        map
+    }
+
+    /// Builds a map from rows of tiles, the first row being the top of the map.
+    pub fn from_rows(rows: &[&[u8]]) -> Self {
+        let height = rows.len();
+        let width = rows.first().map_or(0, |row| row.len());
+        assert!(
+            rows.iter().all(|row| row.len() == width),
+            "all map rows must be the same length"
+        );
+
+        let mut map = Self::new(width, height);
+        for (row, tiles) in rows.iter().enumerate() {
+            let y = height - 1 - row;
+            for (x, &tile) in tiles.iter().enumerate() {
+                map[(x, y)] = tile;
+            }
+        }
+        map
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<u8> {
+        if x < self.width && y < self.height {
+            Some(self.tiles[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    /// Changes the tile of a cell, forgetting the state of any door that was there.
+    pub fn set_tile(&mut self, x: usize, y: usize, tile: u8) {
+        self[(x, y)] = tile;
+        self.doors.remove(&[x, y]);
+    }
+
+    pub fn is_door(&self, x: usize, y: usize) -> bool {
+        self.get(x, y) == Some(DOOR_TILE)
+    }
+
+    pub fn is_see_through(&self, x: usize, y: usize) -> bool {
+        matches!(self.get(x, y), Some(WINDOW_TILE | GRATE_TILE | FENCE_TILE))
+    }
+
+    pub fn is_mirror(&self, x: usize, y: usize) -> bool {
+        self.get(x, y) == Some(MIRROR_TILE)
+    }
+
+    pub fn door(&self, x: usize, y: usize) -> Door {
+        self.doors.get(&[x, y]).copied().unwrap_or_default()
+    }
+
+    pub fn lift(&self, x: usize, y: usize) -> Option<Lift> {
+        self.lifts.get(&[x, y]).copied()
+    }
+
+    /// Puts stairs or a lift in a cell, or takes them away with None.
+    pub fn set_lift(&mut self, x: usize, y: usize, lift: Option<Lift>) {
+        assert!(x < self.width && y < self.height, "cell out of bounds");
+        match lift {
+            Some(lift) => self.lifts.insert([x, y], lift),
+            None => self.lifts.remove(&[x, y]),
+        };
+    }
+
+    pub fn lifts(&self) -> impl Iterator<Item = ([usize; 2], Lift)> + '_ {
+        self.lifts.iter().map(|(&cell, &lift)| (cell, lift))
+    }
+
+    /// Where the portal on a face of a cell leads, if there is one.
+    pub fn portal(&self, cell: [usize; 2], face: Face) -> Option<Portal> {
+        self.portals.get(&(cell, face)).copied()
+    }
+
+    /// Turns a face of a cell into a portal, or back into a wall with None.
+    /// Portals only work one way, so a pair of faces that lead to each other
+    /// each need setting.
+    pub fn set_portal(&mut self, cell: [usize; 2], face: Face, portal: Option<Portal>) {
+        assert!(
+            cell[0] < self.width && cell[1] < self.height,
+            "cell out of bounds"
+        );
+        match portal {
+            Some(portal) => self.portals.insert((cell, face), portal),
+            None => self.portals.remove(&(cell, face)),
+        };
+    }
+
+    pub fn portals(&self) -> impl Iterator<Item = ([usize; 2], Face, Portal)> + '_ {
+        self.portals
+            .iter()
+            .map(|(&(cell, face), &portal)| (cell, face, portal))
+    }
+
+    /// Doors span the gap between the walls either side of them. A door with
+    /// walls to its east and west runs along the x axis, across the middle of
+    /// its cell, otherwise it runs along the y axis.
+    pub fn door_runs_along_x(&self, x: usize, y: usize) -> bool {
+        x > 0 && self.is_solid(x - 1, y) && self.is_solid(x + 1, y)
+    }
+
+    /// Starts a door opening if it is closed or closing, and closing otherwise.
+    pub fn toggle_door(&mut self, x: usize, y: usize) {
+        if !self.is_door(x, y) {
+            return;
+        }
+        let door = self.doors.entry([x, y]).or_default();
+        door.state = match door.state {
+            DoorState::Closed | DoorState::Closing => DoorState::Opening,
+            DoorState::Open | DoorState::Opening => DoorState::Closing,
+        };
+    }
+
+    /// Moves every opening or closing door along by `amount`.
+    pub fn update_doors(&mut self, amount: f32) {
+        for door in self.doors.values_mut() {
+            match door.state {
+                DoorState::Opening => {
+                    door.open = (door.open + amount).min(1.0);
+                    if door.open >= 1.0 {
+                        door.state = DoorState::Open;
+                    }
+                }
+                DoorState::Closing => {
+                    door.open = (door.open - amount).max(0.0);
+                    if door.open <= 0.0 {
+                        door.state = DoorState::Closed;
+                    }
+                }
+                DoorState::Closed | DoorState::Open => {}
+            }
+        }
+    }
+
+    /// Starts a push wall sliding one cell at a time in `direction`. Returns
+    /// false if there is no push wall in the cell or nowhere for it to go.
+    pub fn push_wall(&mut self, x: usize, y: usize, direction: [isize; 2]) -> bool {
+        if self.get(x, y) != Some(PUSH_WALL_TILE) {
+            return false;
+        }
+        let wall = PushWall {
+            cell: [x, y],
+            direction,
+            offset: 0.0,
+            remaining: PUSH_WALL_DISTANCE,
+            tile: PUSH_WALL_TILE,
+        };
+        if !self.is_free(wall.next_cell()) {
+            return false;
+        }
+        self[(x, y)] = 0;
+        self.push_walls.push(wall);
+        true
+    }
+
+    pub fn add_segment(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn ambient(&self) -> [f32; 3] {
+        self.ambient
+    }
+
+    pub fn set_ambient(&mut self, ambient: [f32; 3]) {
+        self.ambient = ambient;
+    }
+
+    pub fn lightmap(&self) -> Option<&Lightmap> {
+        self.lightmap.as_ref()
+    }
+
+    /// Bakes the ambient light and the lights that are not dynamic into a
+    /// lightmap kept with the map. Changes to the walls or static lights after
+    /// this only show once the map is baked again.
+    pub fn bake_lightmap(&mut self, samples: usize) {
+        self.lightmap = Some(Lightmap::bake(self, samples));
+    }
+
+    pub fn push_walls(&self) -> &[PushWall] {
+        &self.push_walls
+    }
+
+    /// The moving push wall covering any part of a cell.
+    pub fn push_wall_at(&self, x: usize, y: usize) -> Option<&PushWall> {
+        self.push_walls.iter().find(|wall| wall.occupies(x, y))
+    }
+
+    /// Whether a push wall can slide into a cell.
+    fn is_free(&self, [x, y]: [usize; 2]) -> bool {
+        !self.is_solid(x, y) && self.push_wall_at(x, y).is_none()
+    }
+
+    /// Whether the moving push wall at `index` can slide into a cell, which
+    /// no other moving push wall covers any part of.
+    fn is_free_for(&self, index: usize, [x, y]: [usize; 2]) -> bool {
+        !self.is_solid(x, y)
+            && self
+                .push_walls
+                .iter()
+                .enumerate()
+                .all(|(i, wall)| i == index || !wall.occupies(x, y))
+    }
+
+    /// Slides every moving push wall along by `amount`, writing those that
+    /// come to rest back into the map. Each wall stops short of the cells of
+    /// every other wall, whether or not that wall has moved yet.
+    pub fn update_push_walls(&mut self, amount: f32) {
+        let mut i = 0;
+        while i < self.push_walls.len() {
+            let mut wall = self.push_walls[i];
+            wall.offset += amount;
+            let mut resting = false;
+            while wall.offset >= 1.0 {
+                wall.cell = wall.next_cell();
+                wall.offset -= 1.0;
+                wall.remaining -= 1;
+                if wall.remaining == 0 || !self.is_free_for(i, wall.next_cell()) {
+                    resting = true;
+                    break;
+                }
+            }
+            if resting {
+                let [x, y] = wall.cell;
+                self[(x, y)] = wall.tile;
+                self.push_walls.remove(i);
+            } else {
+                self.push_walls[i] = wall;
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether the player is kept out of a cell. Doors only let the player
+    /// through once they are fully open.
+    pub fn blocks_movement(&self, x: usize, y: usize) -> bool {
+        if self.push_wall_at(x, y).is_some() {
+            return true;
+        }
+        if self.is_door(x, y) {
+            return self.door(x, y).state != DoorState::Open;
+        }
+        self.is_solid(x, y)
+    }
+
+    pub fn fog(&self) -> Fog {
+        self.fog
+    }
+
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.fog = fog;
+    }
+
+    pub fn floor(&self, x: usize, y: usize) -> u8 {
+        self.floors[self.cell_index(x, y)]
+    }
+
+    pub fn set_floor(&mut self, x: usize, y: usize, floor: u8) {
+        let i = self.cell_index(x, y);
+        self.floors[i] = floor;
+    }
+
+    pub fn ceiling(&self, x: usize, y: usize) -> u8 {
+        self.ceilings[self.cell_index(x, y)]
+    }
+
+    pub fn set_ceiling(&mut self, x: usize, y: usize, ceiling: u8) {
+        let i = self.cell_index(x, y);
+        self.ceilings[i] = ceiling;
+    }
+
+    /// How tall the wall in a cell is, 1.0 being a standard wall.
+    pub fn wall_height(&self, x: usize, y: usize) -> f32 {
+        self.heights[self.cell_index(x, y)]
+    }
+
+    pub fn set_wall_height(&mut self, x: usize, y: usize, height: f32) {
+        assert!(height > 0.0, "walls must have some height");
+        let i = self.cell_index(x, y);
+        self.heights[i] = height;
+        self.update_tallest();
+    }
+
+    /// The height of the tallest wall on the map. Only walls this tall hide
+    /// everything behind them.
+    pub fn tallest_wall(&self) -> f32 {
+        self.tallest
+    }
+
+    fn update_tallest(&mut self) {
+        self.tallest = self.heights.iter().copied().fold(1.0, f32::max);
+    }
+
+    /// Whether a cell is a wall that cannot be seen through. Doors and
+    /// see-through tiles let some of what is behind them show.
+    pub fn is_opaque(&self, x: usize, y: usize) -> bool {
+        self.is_solid(x, y) && !self.is_door(x, y) && !self.is_see_through(x, y)
+    }
+
+    /// Gives every cell of the map the same floor and ceiling.
+    pub fn fill_floor_and_ceiling(&mut self, floor: u8, ceiling: u8) {
+        self.floors.fill(floor);
+        self.ceilings.fill(ceiling);
+    }
+
+    fn cell_index(&self, x: usize, y: usize) -> usize {
+        assert!(x < self.width && y < self.height, "cell out of bounds");
+        y * self.width + x
+    }
+
+    /// Whether a cell blocks movement and rays. Cells outside the map are solid.
+    pub fn is_solid(&self, x: usize, y: usize) -> bool {
+        self.get(x, y).is_none_or(|tile| tile != 0)
+    }
+
+    /// Resizes the map in place, keeping the tiles that fit in the new size
+    /// and filling any new cells with `fill`. New cells have no floor or ceiling.
+    /// A baked lightmap is baked again for the new cells, with as many samples
+    /// as before.
+    pub fn resize(&mut self, width: usize, height: usize, fill: u8) {
+        assert!(width > 0 && height > 0, "map must have at least one cell");
+        self.tiles = self.resize_layer(&self.tiles, width, height, fill);
+        self.floors = self.resize_layer(&self.floors, width, height, 0);
+        self.ceilings = self.resize_layer(&self.ceilings, width, height, 0);
+        self.heights = self.resize_layer(&self.heights, width, height, 1.0);
+        self.doors.retain(|&[x, y], _| x < width && y < height);
+        self.lifts.retain(|&[x, y], _| x < width && y < height);
+        self.portals
+            .retain(|&([x, y], _), _| x < width && y < height);
+        self.lights
+            .retain(|light| light.pos[0] < width as f32 && light.pos[1] < height as f32);
+        self.width = width;
+        self.height = height;
+        // A push wall that would slide out of the map comes to rest where it is
+        let in_bounds = |[x, y]: [usize; 2]| x < width && y < height;
+        for wall in std::mem::take(&mut self.push_walls) {
+            if !in_bounds(wall.cell) {
+                continue;
+            }
+            if in_bounds(wall.next_cell()) {
+                self.push_walls.push(wall);
+            } else {
+                let [x, y] = wall.cell;
+                self[(x, y)] = wall.tile;
+            }
+        }
+        self.update_tallest();
+        // The baked light no longer lines up with the cells
+        if let Some(samples) = self.lightmap.as_ref().map(Lightmap::samples) {
+            self.bake_lightmap(samples);
+        }
+    }
+
+    fn resize_layer<T: Copy>(&self, layer: &[T], width: usize, height: usize, fill: T) -> Vec<T> {
+        let mut resized = vec![fill; width * height];
+        for y in 0..height.min(self.height) {
+            for x in 0..width.min(self.width) {
+                resized[y * width + x] = layer[y * self.width + x];
+            }
+        }
+        resized
+    }
+
+    /// Cells are kept square, so the longest side of the map spans the whole panel.
+    pub fn cell_width(&self) -> f32 {
+        COORD_SIZE as f32 / self.width.max(self.height) as f32
+    }
+
+    /// Converts a world position into map coordinates, where each cell is one unit wide.
+    pub fn to_map_space(&self, pos: [f32; 2]) -> [f32; 2] {
+        let extent = COORD_SIZE as f32 / self.cell_width();
+        [
+            convert_range(pos[0], [-1.0, 1.0], [0.0, extent]),
+            convert_range(pos[1], [-1.0, 1.0], [0.0, extent]),
+        ]
+    }
+
+    /// Converts a position in map coordinates back into a world position.
+    pub fn to_world_space(&self, pos: [f32; 2]) -> [f32; 2] {
+        let cell_width = self.cell_width();
+        [pos[0] * cell_width - 1.0, pos[1] * cell_width - 1.0]
+    }
+
+    /// The world position of the centre of a cell.
+    pub fn cell_center(&self, x: usize, y: usize) -> [f32; 2] {
+        let cell_width = self.cell_width();
+        [
+            (x as f32 * cell_width) + (cell_width / 2.0) - 1.0,
+            (y as f32 * cell_width) + (cell_width / 2.0) - 1.0,
+        ]
+    }
+}
+
+impl Index<(usize, usize)> for GameMap {
+    type Output = u8;
+
+    fn index(&self, (x, y): (usize, usize)) -> &u8 {
+        &self.tiles[self.cell_index(x, y)]
+    }
+}
+
+impl IndexMut<(usize, usize)> for GameMap {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut u8 {
+        let i = self.cell_index(x, y);
+        &mut self.tiles[i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_walls_sliding_towards_each_other_stop_side_by_side() {
+        let mut map = GameMap::new(10, 10);
+        map[(3, 5)] = PUSH_WALL_TILE;
+        map[(6, 5)] = PUSH_WALL_TILE;
+        assert!(map.push_wall(3, 5, [1, 0]));
+        assert!(map.push_wall(6, 5, [-1, 0]));
+
+        map.update_push_walls(1.0);
+        map.update_push_walls(1.0);
+        assert!(map.push_walls().is_empty());
+        assert_eq!(map.get(4, 5), Some(PUSH_WALL_TILE));
+        assert_eq!(map.get(5, 5), Some(PUSH_WALL_TILE));
+        assert_eq!(map.get(3, 5), Some(0));
+        assert_eq!(map.get(6, 5), Some(0));
+    }
+
+    #[test]
+    fn resizing_bakes_the_lightmap_again() {
+        let mut map = GameMap::bordered(10, 10);
+        map.add_light(Light::new([4.5, 4.5], [1.0; 3], 4.0, 1.0));
+        map.resize(12, 10, 0);
+        assert!(map.lightmap().is_none());
+
+        map.bake_lightmap(2);
+        map.resize(8, 10, 0);
+        let lightmap = map.lightmap().expect("the lightmap was not baked again");
+        assert_eq!(lightmap.samples(), 2);
+        assert_eq!(*lightmap, Lightmap::bake(&map, 2));
+    }
+
+    #[test]
+    fn resizing_settles_push_walls_that_would_slide_out() {
+        let mut map = GameMap::new(10, 10);
+        map[(7, 5)] = PUSH_WALL_TILE;
+        assert!(map.push_wall(7, 5, [1, 0]));
+        map.update_push_walls(0.5);
+
+        map.resize(8, 10, 0);
+        assert!(map.push_walls().is_empty());
+        assert_eq!(map.get(7, 5), Some(PUSH_WALL_TILE));
+        map.update_push_walls(1.0);
+        assert_eq!(map.get(7, 5), Some(PUSH_WALL_TILE));
+    }
+
+    #[test]
+    fn resizing_keeps_push_walls_that_still_fit() {
+        let mut map = GameMap::new(10, 10);
+        map[(3, 5)] = PUSH_WALL_TILE;
+        assert!(map.push_wall(3, 5, [1, 0]));
+
+        map.resize(8, 10, 0);
+        assert_eq!(map.push_walls().len(), 1);
+        map.update_push_walls(1.0);
+        assert_eq!(map.push_walls()[0].cell, [4, 5]);
+    }
+}
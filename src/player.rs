@@ -1,4 +1,8 @@
-use crate::{game::{GameMap, CELL_WIDTH, MOVE_AMOUNT}, ray::Ray};
+use crate::{
+    game::{CROUCH_EYE_HEIGHT, EYE_HEIGHT, GRAVITY, JUMP_SPEED, MAX_PITCH, MOVE_AMOUNT},
+    map::GameMap,
+    ray::Ray,
+};
 
 #[derive(Debug)]
 pub struct Player {
@@ -6,24 +10,78 @@ pub struct Player {
     pub width: f32,
     pub rotation: f32,
     pub view: Ray,
-
+    /// How far the view is tilted up, as the distance the horizon moves down
+    /// the screen. Negative when looking down.
+    pub pitch: f32,
+    /// Height above the floor while jumping, in standard wall heights.
+    pub jump: f32,
+    /// Speed the player is rising at, or falling at if negative.
+    pub jump_speed: f32,
+    pub crouching: bool,
 }
 
 
 pub const LINE_LENGTH: f32 = 0.05;
 impl Player {
-    fn validate_move(&mut self, x: f32, y: f32, map: GameMap) -> bool {
-        let n = map.len();
-        for i in 0..n {
-            for j in 0..n {
-                if map[i][j] == 1 {
+    /// Height of the player's eyes above the floor, in standard wall heights.
+    pub fn eye_height(&self) -> f32 {
+        let standing = if self.crouching {
+            CROUCH_EYE_HEIGHT
+        } else {
+            EYE_HEIGHT
+        };
+        standing + self.jump
+    }
+
+    /// Leaps off the floor, unless already in the air.
+    pub fn start_jump(&mut self) {
+        if self.jump == 0.0 {
+            self.jump_speed = JUMP_SPEED;
+        }
+    }
+
+    /// Carries a jump on for a frame, landing back on the floor.
+    pub fn update_jump(&mut self) {
+        if self.jump == 0.0 && self.jump_speed == 0.0 {
+            return;
+        }
+        self.jump += self.jump_speed;
+        self.jump_speed -= GRAVITY;
+        if self.jump <= 0.0 {
+            self.jump = 0.0;
+            self.jump_speed = 0.0;
+        }
+    }
+
+    /// Tilts the view by `amount`, keeping the horizon on the screen.
+    pub fn look(&mut self, amount: f32) {
+        self.pitch = (self.pitch + amount).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    fn validate_move(&mut self, x: f32, y: f32, map: &GameMap) -> bool {
+        let cell_width = map.cell_width();
+        // The edge of the map stops the player like a wall, whether or not
+        // it has a border of walls
+        let half_width = self.width / 2.0;
+        let right = map.width() as f32 * cell_width - 1.0;
+        let top = map.height() as f32 * cell_width - 1.0;
+        if x - half_width < -1.0
+            || x + half_width > right
+            || y - half_width < -1.0
+            || y + half_width > top
+        {
+            return false;
+        }
+        for i in 0..map.width() {
+            for j in 0..map.height() {
+                if map.blocks_movement(i, j) {
                     let (beg_x, end_x) = (
-                        i as f32 * CELL_WIDTH - 1.0,
-                        (i as f32 + 1.0) * CELL_WIDTH - 1.0,
+                        i as f32 * cell_width - 1.0,
+                        (i as f32 + 1.0) * cell_width - 1.0,
                     );
                     let (beg_y, end_y) = (
-                        j as f32 * CELL_WIDTH - 1.0,
-                        (j as f32 + 1.0) * CELL_WIDTH - 1.0,
+                        j as f32 * cell_width - 1.0,
+                        (j as f32 + 1.0) * cell_width - 1.0,
                     );
                     let h_p_width = (self.width - 0.05) / 2.0;
                     if x + h_p_width < end_x
@@ -58,9 +116,21 @@ impl Player {
             }
         }
 
+        // Keep the player's body clear of segment walls, while still letting
+        // them step away from one they are already touching
+        let from = map.to_map_space(self.pos);
+        let to = map.to_map_space([x, y]);
+        let radius = self.width / 2.0 / cell_width;
+        for segment in map.segments() {
+            let distance = segment.distance_to(to);
+            if distance < radius && distance < segment.distance_to(from) {
+                return false;
+            }
+        }
+
         true
     }
-    pub fn move_forward(&mut self, map: GameMap) {
+    pub fn move_forward(&mut self, map: &GameMap) {
         let rad = self.rotation.to_radians();
         let hypot = MOVE_AMOUNT;
 
@@ -77,7 +147,7 @@ impl Player {
         }
     }
 
-    pub fn move_backward(&mut self, map: GameMap) {
+    pub fn move_backward(&mut self, map: &GameMap) {
         let rad = self.rotation.to_radians();
         let hypot = MOVE_AMOUNT;
 
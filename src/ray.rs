@@ -1,126 +1,959 @@
-use crate::{
-    game::{GameMap, CELL_WIDTH},
-    util::convert_range,
-};
+use crate::map::{GameMap, PushWall, Segment, MIRROR_TILE};
 
-#[derive(Debug)]
+/// How many portals a ray goes through before it gives up, so that portals
+/// facing each other cannot trap it forever.
+pub const MAX_PORTALS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: [f32; 2],
     pub length: f32,
     pub rotation: f32,
 }
 
-#[derive(Debug)]
-enum RayDirection {
-    TopLeft,
-    TopRight,
-    BottomLeft,
-    BottomRight,
+/// The side of a cell that a ray struck, named after the direction the face points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Face {
+    North,
+    South,
+    East,
+    West,
 }
 
-#[derive(Debug)]
-struct StepLength {
-    using_x: f32,
-    using_y: f32,
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    /// Distance travelled by the ray in world units.
+    pub distance: f32,
+    pub cell: [usize; 2],
+    /// World space position where the ray struck the face.
+    pub point: [f32; 2],
+    pub face: Face,
+    /// How far along the face the hit landed, from 0.0 on the left edge to 1.0
+    /// on the right edge as seen by someone looking at the face.
+    pub tex_x: f32,
+    pub tile: u8,
+    /// How tall the wall struck is, 1.0 being a standard wall.
+    pub height: f32,
+    /// Distance travelled by the ray when it leaves the struck cell, where
+    /// the far edge of a low wall's top is.
+    pub exit: f32,
+    /// World space position where the ray leaves the struck cell.
+    pub exit_point: [f32; 2],
 }
 
-#[derive(Debug, Clone, Copy)]
-struct StepDirection {
-    x: f32,
-    y: f32,
+impl Face {
+    /// Unit vector pointing out of the face, in map space.
+    pub fn normal(self) -> [f32; 2] {
+        match self {
+            Face::North => [0.0, 1.0],
+            Face::South => [0.0, -1.0],
+            Face::East => [1.0, 0.0],
+            Face::West => [-1.0, 0.0],
+        }
+    }
+
+    /// Angle of the face's normal in degrees, anticlockwise from east.
+    pub fn angle(self) -> f32 {
+        match self {
+            Face::East => 0.0,
+            Face::North => 90.0,
+            Face::West => 180.0,
+            Face::South => 270.0,
+        }
+    }
+
+    /// Converts a position along the face, measured along the map's axis,
+    /// into a texture coordinate running left to right as seen from outside.
+    fn tex_x(self, along: f32) -> f32 {
+        match self {
+            Face::West | Face::North => 1.0 - along,
+            Face::East | Face::South => along,
+        }
+    }
 }
 
-pub enum Lighting {
-    Shaded,
-    Lit,
+/// One stretch of a ray, from where it started, came out of a portal or
+/// bounced off a mirror to where it was stopped, went into the next portal or
+/// mirror or ran out of distance.
+#[derive(Debug, Clone)]
+pub struct Leg {
+    /// Index of the storey whose map the leg crosses.
+    pub storey: usize,
+    pub ray: Ray,
+    /// Distance the ray had travelled before this leg.
+    pub start: f32,
+    /// Distance travelled along this leg.
+    pub length: f32,
+    /// Hits the leg passes nearest first, at distances measured along the leg.
+    pub passed: Vec<RayHit>,
+    pub stop: Option<RayHit>,
+    /// How many mirrors the ray bounced off before this leg.
+    pub bounces: usize,
 }
 
-type RayLength = StepLength;
-type Position = StepDirection;
+/// A stretch of an open cell crossed by a ray, used to cast the floor and ceiling.
+#[derive(Debug, Clone, Copy)]
+pub struct CellSpan {
+    pub cell: [usize; 2],
+    /// Distance along the ray at which it enters the cell.
+    pub entry: f32,
+    /// Distance along the ray at which it leaves the cell.
+    pub exit: f32,
+    pub entry_point: [f32; 2],
+    pub exit_point: [f32; 2],
+}
 
 impl Ray {
-    fn get_direction(&self) -> RayDirection {
-        match self.rotation % 360.0 {
-            r if r > 0.0 && r <= 90.0 => RayDirection::TopLeft,
-            r if r > 90.0 && r <= 180.0 => RayDirection::BottomLeft,
-            r if r > 180.0 && r <= 270.0 => RayDirection::BottomRight,
-            _ => RayDirection::TopRight,
+    /// Unit vector the ray points along. Quarter turns are exact, so that a
+    /// ray along an axis never drifts into the next row of cells.
+    pub fn direction(&self) -> [f32; 2] {
+        let rotation = self.rotation.rem_euclid(360.0);
+        let (sin, cos) = match rotation {
+            0.0 => (0.0, 1.0),
+            90.0 => (1.0, 0.0),
+            180.0 => (0.0, -1.0),
+            270.0 => (-1.0, 0.0),
+            r => r.to_radians().sin_cos(),
+        };
+        [-sin, cos]
+    }
+
+    /// A ray from `from` pointing at `to`, as long as the distance between them.
+    pub fn towards(from: [f32; 2], to: [f32; 2]) -> Self {
+        let [x, y] = [to[0] - from[0], to[1] - from[1]];
+        Ray {
+            origin: from,
+            length: x.hypot(y),
+            rotation: (-x).atan2(y).to_degrees().rem_euclid(360.0),
         }
     }
 
-    pub fn collision(&self, map: GameMap) -> (f32, Lighting) {
-        let unit = CELL_WIDTH as f32;
+    /// World space position after travelling `distance` along the ray.
+    pub fn point_at(&self, distance: f32) -> [f32; 2] {
+        let [x, y] = self.direction();
+        [self.origin[0] + x * distance, self.origin[1] + y * distance]
+    }
 
-        let radians = self.rotation.to_radians();
+    /// The first wall face that stops the ray within `max_distance`, however
+    /// short the wall, or None if it leaves the map or runs out of distance first.
+    pub fn collision(&self, map: &GameMap, max_distance: f32) -> Option<RayHit> {
+        self.trace(map, max_distance, None).1
+    }
 
-        let origin = Position {
-            x: convert_range(self.origin[0], [-1.0, 1.0], [0.0, map.len() as f32]),
-            y: convert_range(self.origin[1], [-1.0, 1.0], [0.0, map.len() as f32]),
-        }; // Using map coordinate space as it is easier when using a GameMap
+    /// Follows the ray across the storeys of a level, starting on `storey`,
+    /// carrying it on from the linked face whenever it hits a portal and
+    /// bouncing it off up to `max_bounces` mirrors. `eye` is how many wall
+    /// heights above the floor the ray is seen from, which portals carry over
+    /// to the floor they lead to.
+    pub fn legs(
+        &self,
+        maps: &[GameMap],
+        storey: usize,
+        eye: f32,
+        max_distance: f32,
+        max_bounces: usize,
+    ) -> Vec<Leg> {
+        let mut legs = vec![];
+        let mut ray = *self;
+        let mut storey = storey;
+        let mut start = 0.0;
+        let mut portals = 0;
+        let mut bounces = 0;
+        while let Some(map) = maps.get(storey) {
+            let (mut passed, mut stop) = ray.hits(map, eye, max_distance - start);
+            // Portals and mirrors are gone through whether or not the wall
+            // they are on hides what is behind it
+            let portal = |hit: &RayHit| {
+                map.portal(hit.cell, hit.face)
+                    .filter(|portal| portals < MAX_PORTALS && portal.storey < maps.len())
+            };
+            let mirror = |hit: &RayHit| {
+                let [x, y] = hit.cell;
+                map.is_mirror(x, y) && hit.tile == MIRROR_TILE && bounces < max_bounces
+            };
+            if let Some(i) = passed
+                .iter()
+                .position(|hit| portal(hit).is_some() || mirror(hit))
+            {
+                stop = Some(passed[i]);
+                passed.truncate(i);
+            }
+            let mut leg = Leg {
+                storey,
+                ray,
+                start,
+                length: stop.map_or(max_distance - start, |hit| hit.distance),
+                passed,
+                stop,
+                bounces,
+            };
+            let Some(hit) = stop else {
+                legs.push(leg);
+                break;
+            };
 
-        let ray_direction = self.get_direction();
+            if let Some(portal) = portal(&hit) {
+                let target = &maps[portal.storey];
+                let point = portal.carry(hit.cell, hit.face, map.to_map_space(hit.point));
+                ray = Ray {
+                    origin: target.to_world_space(point),
+                    rotation: (ray.rotation + portal.turn(hit.face)).rem_euclid(360.0),
+                    ..ray
+                };
+                storey = portal.storey;
+                portals += 1;
+            } else if mirror(&hit) {
+                ray = ray.reflect(&hit);
+                bounces += 1;
+            } else {
+                legs.push(leg);
+                break;
+            }
+            // The face the leg ended on is not drawn, as the next leg is seen through it
+            leg.stop = None;
+            legs.push(leg);
+            start += hit.distance;
+        }
+        legs
+    }
 
-        let step_direction: StepDirection = match ray_direction {
-            RayDirection::TopLeft => StepDirection { x: -1.0, y: 1.0 },
-            RayDirection::TopRight => StepDirection { x: 1.0, y: 1.0 },
-            RayDirection::BottomLeft => StepDirection { x: -1.0, y: -1.0 },
-            RayDirection::BottomRight => StepDirection { x: 1.0, y: -1.0 },
+    /// The ray bounced off the face it struck, setting out from where it struck it.
+    fn reflect(&self, hit: &RayHit) -> Ray {
+        let rotation = match hit.face {
+            Face::East | Face::West => -self.rotation,
+            Face::North | Face::South => 180.0 - self.rotation,
         };
+        Ray {
+            origin: hit.point,
+            rotation: rotation.rem_euclid(360.0),
+            ..*self
+        }
+    }
 
-        let step_length = StepLength {
-            using_x: (unit.powi(2) + (unit / radians.tan()).powi(2)).sqrt(),
-            using_y: (unit.powi(2) + (unit * radians.tan()).powi(2)).sqrt(),
-        };
+    /// Every hit that can be seen along the ray from an eye `eye` wall heights
+    /// above the floor, nearest first, followed by the hit that stops it, if it
+    /// is stopped within `max_distance`. The passed hits are see-through tiles
+    /// and walls that show over the nearer walls in front of them.
+    pub fn hits(
+        &self,
+        map: &GameMap,
+        eye: f32,
+        max_distance: f32,
+    ) -> (Vec<RayHit>, Option<RayHit>) {
+        self.trace(map, max_distance, Some(eye))
+    }
+
+    /// Follows the ray until it is stopped by the grid or a segment wall. Seen
+    /// from an eye height, walls can be seen over and the hits passed on the
+    /// way are collected; otherwise the first wall stops the ray.
+    fn trace(
+        &self,
+        map: &GameMap,
+        max_distance: f32,
+        eye: Option<f32>,
+    ) -> (Vec<RayHit>, Option<RayHit>) {
+        let (mut passed, stop) = self.trace_grid(map, max_distance, eye);
+        let segment = map
+            .segments()
+            .iter()
+            .filter_map(|segment| self.segment_hit(map, segment))
+            .filter(|hit| hit.distance <= max_distance)
+            .min_by(|a, b| a.distance.total_cmp(&b.distance));
+        match (segment, stop) {
+            (Some(hit), stop) if stop.is_none_or(|stop| hit.distance < stop.distance) => {
+                passed.retain(|passed| passed.distance < hit.distance);
+                (passed, Some(hit))
+            }
+            _ => (passed, stop),
+        }
+    }
+
+    /// Follows the ray through the grid until it is stopped. Cells outside the
+    /// map are open, so a ray that leaves the map without coming back hits nothing.
+    fn trace_grid(
+        &self,
+        map: &GameMap,
+        max_distance: f32,
+        eye: Option<f32>,
+    ) -> (Vec<RayHit>, Option<RayHit>) {
+        let mut traversal = Traversal::new(self, map, max_distance);
+        let mut passed = vec![];
+        // The tallest wall so far that reaches up to the eye, hiding anything
+        // behind it that is no taller
+        let mut cover = f32::NEG_INFINITY;
+        let within = |hit: RayHit| Some(hit).filter(|hit| hit.distance <= max_distance);
+
+        let mut previous_tile = traversal.cell().and_then(|[x, y]| map.get(x, y));
+        while let Some(step) = traversal.step() {
+            let Some([i, j]) = step.cell else {
+                previous_tile = None;
+                continue;
+            };
+            if let Some(wall) = map.push_wall_at(i, j) {
+                if let Some(hit) = self.push_wall_hit(map, wall) {
+                    return (passed, within(hit));
+                }
+            }
+            if map.is_door(i, j) {
+                match self.door_hit(map, [i, j]) {
+                    Some(hit) => return (passed, within(hit)),
+                    None => continue,
+                }
+            }
 
-        let mut position = StepDirection {
-            y: origin.y.floor(),
-            x: origin.x.floor(),
+            let tile = map.get(i, j);
+            if map.is_see_through(i, j) {
+                // Neighbouring see-through cells of the same kind are drawn as one
+                let height = map.wall_height(i, j);
+                if eye.is_some() && tile != previous_tile && height > cover {
+                    passed.push(self.face_hit(map, step, [i, j]));
+                }
+                previous_tile = tile;
+                continue;
+            }
+            previous_tile = tile;
+            if map.is_solid(i, j) {
+                let exit = traversal.exit();
+                let hit = RayHit {
+                    exit,
+                    exit_point: self.point_at(exit),
+                    ..self.face_hit(map, step, [i, j])
+                };
+                let Some(eye) = eye else {
+                    return (passed, Some(hit));
+                };
+                if hit.height >= eye && hit.height >= map.tallest_wall() {
+                    return (passed, Some(hit));
+                }
+                if hit.height > cover {
+                    passed.push(hit);
+                }
+                if hit.height >= eye {
+                    cover = cover.max(hit.height);
+                }
+            }
+        }
+        (passed, None)
+    }
+
+    /// The hit made by a ray on the face of the cell it entered at `step`.
+    fn face_hit(&self, map: &GameMap, step: Step, cell: [usize; 2]) -> RayHit {
+        let [i, j] = cell;
+        let point = self.point_at(step.distance);
+        let [hit_x, hit_y] = map.to_map_space(point);
+        let along = match step.face {
+            Face::West | Face::East => hit_y.fract(),
+            Face::South | Face::North => hit_x.fract(),
         };
 
-        let mut ray_length = match ray_direction {
-            RayDirection::TopLeft => RayLength {
-                using_x: (origin.x - position.x) * step_length.using_x,
-                using_y: ((position.y + 1.0) - origin.y) * step_length.using_y,
-            },
-            RayDirection::TopRight => RayLength {
-                using_x: ((position.x + 1.0) - origin.x) * step_length.using_x,
-                using_y: ((position.y + 1.0) - origin.y) * step_length.using_y,
-            },
-            RayDirection::BottomLeft => RayLength {
-                using_x: (origin.x - position.x) * step_length.using_x,
-                using_y: (origin.y - position.y) * step_length.using_y,
-            },
-            RayDirection::BottomRight => RayLength {
-                using_x: ((position.x + 1.0) - origin.x) * step_length.using_x,
-                using_y: (origin.y - position.y) * step_length.using_y,
-            },
+        RayHit {
+            distance: step.distance,
+            cell,
+            point,
+            face: step.face,
+            tex_x: step.face.tex_x(along),
+            tile: map.get(i, j).unwrap_or(0),
+            height: map.get(i, j).map_or(1.0, |_| map.wall_height(i, j)),
+            exit: step.distance,
+            exit_point: point,
+        }
+    }
+
+    /// Checks the ray against a free-standing segment wall. The face is named
+    /// after whichever axis the side facing the ray points along most.
+    fn segment_hit(&self, map: &GameMap, segment: &Segment) -> Option<RayHit> {
+        let direction = self.direction();
+        let origin = map.to_map_space(self.origin);
+        let cross = |a: [f32; 2], b: [f32; 2]| a[0] * b[1] - a[1] * b[0];
+
+        let along = [
+            segment.end[0] - segment.start[0],
+            segment.end[1] - segment.start[1],
+        ];
+        let denominator = cross(direction, along);
+        if denominator.abs() < f32::EPSILON {
+            return None;
+        }
+        let rel = [segment.start[0] - origin[0], segment.start[1] - origin[1]];
+        let distance = cross(rel, along) / denominator;
+        let position = cross(rel, direction) / denominator;
+        if distance < 0.0 || !(0.0..=1.0).contains(&position) {
+            return None;
+        }
+
+        // The normal of the side the ray struck, pointing back towards the ray
+        let mut normal = [-along[1], along[0]];
+        if normal[0] * direction[0] + normal[1] * direction[1] > 0.0 {
+            normal = [-normal[0], -normal[1]];
+        }
+        let face = if normal[0].abs() > normal[1].abs() {
+            if normal[0] > 0.0 {
+                Face::East
+            } else {
+                Face::West
+            }
+        } else if normal[1] > 0.0 {
+            Face::North
+        } else {
+            Face::South
+        };
+        // The texture repeats once per cell of length, running left to right
+        // for someone facing the side that was struck
+        let right = [-normal[1], normal[0]];
+        let length = segment.length();
+        let tex_x = if along[0] * right[0] + along[1] * right[1] >= 0.0 {
+            (position * length).fract()
+        } else {
+            ((1.0 - position) * length).fract()
         };
+        let distance = distance * map.cell_width();
+        let point = self.point_at(distance);
+        let [x, y] = map.to_map_space(point);
+
+        Some(RayHit {
+            distance,
+            cell: [x.max(0.0) as usize, y.max(0.0) as usize],
+            point,
+            face,
+            tex_x,
+            tile: segment.tile,
+            height: 1.0,
+            exit: distance,
+            exit_point: point,
+        })
+    }
+
+    /// Checks the ray against a push wall part way between two cells.
+    fn push_wall_hit(&self, map: &GameMap, wall: &PushWall) -> Option<RayHit> {
+        let direction = self.direction();
+        let origin = map.to_map_space(self.origin);
+        let (min, max) = wall.bounds();
 
-        let mut tile_found = false;
-        let max_iter = 50;
-        let mut iter = 0;
-        let mut final_length = 0.0;
-        let mut lighting: Lighting = Lighting::Shaded;
-        while !tile_found && iter < max_iter {
-            if ray_length.using_x < ray_length.using_y {
-                position.x += step_direction.x;
-                final_length = ray_length.using_x;
-                lighting = Lighting::Shaded;
-                ray_length.using_x += step_length.using_x;
+        // Distances at which the ray enters and leaves the wall along each axis
+        let mut entry = [f32::NEG_INFINITY; 2];
+        let mut exit = [f32::INFINITY; 2];
+        for axis in 0..2 {
+            if direction[axis] == 0.0 {
+                if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let near = (min[axis] - origin[axis]) / direction[axis];
+            let far = (max[axis] - origin[axis]) / direction[axis];
+            entry[axis] = near.min(far);
+            exit[axis] = near.max(far);
+        }
+        let distance = entry[0].max(entry[1]);
+        if distance > exit[0].min(exit[1]) || distance < 0.0 {
+            return None;
+        }
+
+        let (face, along) = if entry[0] > entry[1] {
+            let along = origin[1] + direction[1] * distance - min[1];
+            let face = if direction[0] > 0.0 {
+                Face::West
+            } else {
+                Face::East
+            };
+            (face, along)
+        } else {
+            let along = origin[0] + direction[0] * distance - min[0];
+            let face = if direction[1] > 0.0 {
+                Face::South
             } else {
-                position.y += step_direction.y;
-                final_length = ray_length.using_y;
-                lighting = Lighting::Lit;
-                ray_length.using_y += step_length.using_y;
+                Face::North
+            };
+            (face, along)
+        };
+        let distance = distance * map.cell_width();
+        let point = self.point_at(distance);
+
+        Some(RayHit {
+            distance,
+            cell: wall.cell,
+            point,
+            face,
+            tex_x: face.tex_x(along),
+            tile: wall.tile,
+            height: 1.0,
+            exit: distance,
+            exit_point: point,
+        })
+    }
+
+    /// Checks the ray against the door recessed to the middle of a door cell,
+    /// which it only hits on the part that has not slid open yet.
+    fn door_hit(&self, map: &GameMap, cell: [usize; 2]) -> Option<RayHit> {
+        let [i, j] = cell;
+        let direction = self.direction();
+        let origin = map.to_map_space(self.origin);
+
+        // The axis the door's plane is fixed on, and the axis it runs along
+        let (fixed, along) = if map.door_runs_along_x(i, j) {
+            (1, 0)
+        } else {
+            (0, 1)
+        };
+        if direction[fixed] == 0.0 {
+            return None;
+        }
+
+        let distance = (cell[fixed] as f32 + 0.5 - origin[fixed]) / direction[fixed];
+        let position = origin[along] + direction[along] * distance - cell[along] as f32;
+        let door = map.door(i, j);
+        if distance < 0.0 || !(door.open..=1.0).contains(&position) {
+            return None;
+        }
+
+        let face = match (fixed, direction[fixed] > 0.0) {
+            (0, true) => Face::West,
+            (0, false) => Face::East,
+            (_, true) => Face::South,
+            (_, false) => Face::North,
+        };
+        let distance = distance * map.cell_width();
+        let point = self.point_at(distance);
+
+        Some(RayHit {
+            distance,
+            cell,
+            point,
+            face,
+            // The texture slides along with the door
+            tex_x: position - door.open,
+            tile: map[(i, j)],
+            height: 1.0,
+            exit: distance,
+            exit_point: point,
+        })
+    }
+
+    /// The cells the ray passes through before travelling `max_distance`,
+    /// reaching a wall that hides the floor and ceiling beyond it from an eye
+    /// `eye` wall heights above the floor, or leaving the map, nearest first.
+    /// Doors, see-through tiles and walls below the eye or the ceiling are
+    /// passed through, as the floor and ceiling can be seen around and over them.
+    pub fn spans(&self, map: &GameMap, eye: f32, max_distance: f32) -> Vec<CellSpan> {
+        let mut traversal = Traversal::new(self, map, max_distance);
+        let mut spans = vec![];
+        let mut cell = traversal.cell();
+        let mut entry = 0.0;
+
+        loop {
+            let step = traversal.step();
+            let exit = step.map_or(max_distance, |step| step.distance.min(max_distance));
+            if let Some(cell) = cell {
+                spans.push(CellSpan {
+                    cell,
+                    entry,
+                    exit,
+                    entry_point: self.point_at(entry),
+                    exit_point: self.point_at(exit),
+                });
             }
+            let Some(step) = step else {
+                break;
+            };
+            if let Some([i, j]) = step.cell {
+                if map.is_opaque(i, j) && map.wall_height(i, j) >= eye.max(1.0) {
+                    break;
+                }
+            }
+            if exit >= max_distance {
+                break;
+            }
+            cell = step.cell;
+            entry = step.distance;
+        }
+        spans
+    }
+}
 
-            let (i, j) = (position.x as usize, position.y as usize);
+/// Casts a fan of rays against a borrowed map, returning the hits of each
+/// ray in the same order as `Ray::hits` would.
+pub fn cast_all(
+    rays: &[Ray],
+    map: &GameMap,
+    eye: f32,
+    max_distance: f32,
+) -> Vec<(Vec<RayHit>, Option<RayHit>)> {
+    map_rays(rays, |ray| ray.hits(map, eye, max_distance))
+}
+
+/// Runs `cast` for every ray, keeping the results in order. With the
+/// `parallel` feature the rays are split across threads.
+pub fn map_rays<T: Send>(rays: &[Ray], cast: impl Fn(&Ray) -> T + Sync + Send) -> Vec<T> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        rays.par_iter().map(cast).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        rays.iter().map(cast).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    /// The cell entered, or None if it is outside the map.
+    cell: Option<[usize; 2]>,
+    /// Distance along the ray at which it enters the cell.
+    distance: f32,
+    /// The face of the cell the ray enters through.
+    face: Face,
+}
+
+/// Walks a ray across the grid one cell boundary at a time, in map space.
+struct Traversal {
+    cell: [isize; 2],
+    /// Which way the cell changes along each axis, or 0 if the ray runs
+    /// parallel to that axis.
+    step: [isize; 2],
+    /// Distance along the ray, in world units, to cross a whole cell on each axis.
+    delta: [f32; 2],
+    /// Distance along the ray to the next cell boundary on each axis.
+    next: [f32; 2],
+    max_distance: f32,
+    size: [isize; 2],
+}
 
-            if map[i][j] == 1 {
-                tile_found = true;
+impl Traversal {
+    fn new(ray: &Ray, map: &GameMap, max_distance: f32) -> Self {
+        let unit = map.cell_width();
+        let direction = ray.direction();
+        let origin = map.to_map_space(ray.origin);
+
+        let mut cell = [0; 2];
+        let mut step = [0; 2];
+        let mut delta = [f32::INFINITY; 2];
+        let mut next = [f32::INFINITY; 2];
+        for axis in 0..2 {
+            let floor = origin[axis].floor();
+            cell[axis] = floor as isize;
+            if direction[axis] == 0.0 {
+                continue;
             }
-            iter += 1;
+            delta[axis] = (unit / direction[axis]).abs();
+            (step[axis], next[axis]) = if direction[axis] > 0.0 {
+                (1, (floor + 1.0 - origin[axis]) * delta[axis])
+            } else {
+                (-1, (origin[axis] - floor) * delta[axis])
+            };
+        }
+
+        Self {
+            cell,
+            step,
+            delta,
+            next,
+            max_distance,
+            size: [map.width() as isize, map.height() as isize],
+        }
+    }
+
+    /// The cell the traversal is in, or None if it is outside the map.
+    fn cell(&self) -> Option<[usize; 2]> {
+        let [x, y] = self.cell;
+        let [width, height] = self.size;
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return None;
+        }
+        Some([x as usize, y as usize])
+    }
+
+    /// Distance along the ray at which it leaves the current cell.
+    fn exit(&self) -> f32 {
+        self.next[0].min(self.next[1])
+    }
+
+    /// Whether the traversal is outside the map and not heading back into it,
+    /// so that it can never reach another cell.
+    fn left_map(&self) -> bool {
+        (0..2).any(|axis| {
+            let (cell, step) = (self.cell[axis], self.step[axis]);
+            (cell < 0 && step <= 0) || (cell >= self.size[axis] && step >= 0)
+        })
+    }
+
+    /// Moves into the next cell along the ray, or returns None once the ray
+    /// has travelled its maximum distance or left the map for good.
+    fn step(&mut self) -> Option<Step> {
+        let axis = if self.next[0] < self.next[1] { 0 } else { 1 };
+        let distance = self.next[axis];
+        if distance > self.max_distance || self.left_map() {
+            return None;
+        }
+        self.cell[axis] += self.step[axis];
+        self.next[axis] += self.delta[axis];
+
+        let face = match (axis, self.step[axis] > 0) {
+            (0, true) => Face::West,
+            (0, false) => Face::East,
+            (_, true) => Face::South,
+            (_, false) => Face::North,
+        };
+        Some(Step {
+            cell: self.cell(),
+            distance,
+            face,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Portal, DOOR_TILE, PUSH_WALL_TILE};
+
+    /// A ray heading east from the middle of cell (4, 4).
+    fn east(map: &GameMap) -> Ray {
+        Ray {
+            origin: map.cell_center(4, 4),
+            length: 0.0,
+            rotation: 270.0,
+        }
+    }
+
+    /// A ray setting out from a point in map space.
+    fn ray_from(map: &GameMap, point: [f32; 2], rotation: f32) -> Ray {
+        Ray {
+            origin: map.to_world_space(point),
+            length: 0.0,
+            rotation,
+        }
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{a} is not {b}");
+    }
+
+    /// A bordered map whose east and west walls lead into each other along row 1.
+    fn portal_map() -> GameMap {
+        let mut map = GameMap::bordered(10, 10);
+        let portal = |cell, face| {
+            Some(Portal {
+                storey: 0,
+                cell,
+                face,
+            })
+        };
+        map.set_portal([0, 1], Face::East, portal([9, 1], Face::West));
+        map.set_portal([9, 1], Face::West, portal([0, 1], Face::East));
+        map
+    }
+
+    #[test]
+    fn legs_go_through_portals_on_walls_that_are_seen_over() {
+        let map = portal_map();
+        let ray = Ray {
+            origin: map.cell_center(4, 1),
+            length: 0.0,
+            rotation: 270.0,
+        };
+        let legs = ray.legs(&[map], 0, 0.5, 3.0, 0);
+        assert!(legs.len() > 1);
+
+        let mut tower = portal_map();
+        tower.set_wall_height(0, 8, 2.0);
+        let tower_legs = ray.legs(&[tower], 0, 0.5, 3.0, 0);
+        assert_eq!(tower_legs.len(), legs.len());
+        assert!(tower_legs[..legs.len() - 1]
+            .iter()
+            .all(|leg| leg.stop.is_none() && leg.passed.is_empty()));
+    }
+
+    #[test]
+    fn legs_bounce_off_mirrors_on_walls_that_are_seen_over() {
+        let mut map = GameMap::bordered(10, 10);
+        map[(0, 4)] = MIRROR_TILE;
+        let ray = Ray {
+            origin: map.cell_center(4, 4),
+            length: 0.0,
+            rotation: 90.0,
+        };
+        assert_eq!(ray.legs(&[map.clone()], 0, 0.5, 10.0, 1).len(), 2);
+
+        map.set_wall_height(0, 8, 2.0);
+        let legs = ray.legs(&[map], 0, 0.5, 10.0, 1);
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[1].bounces, 1);
+        assert_eq!(legs[1].passed[0].cell, [9, 4]);
+    }
+
+    #[test]
+    fn collision_stops_at_the_first_wall() {
+        let mut map = GameMap::bordered(10, 10);
+        assert_eq!(east(&map).collision(&map, 10.0).unwrap().cell, [9, 4]);
+
+        // A tall wall elsewhere on the map does not let rays through the others
+        map.set_wall_height(0, 8, 2.0);
+        let hit = east(&map).collision(&map, 10.0).unwrap();
+        assert_eq!(hit.cell, [9, 4]);
+        assert_eq!(hit.face, Face::West);
+
+        // Nor do short walls
+        map[(6, 4)] = 1;
+        map.set_wall_height(6, 4, 0.5);
+        assert_eq!(east(&map).collision(&map, 10.0).unwrap().cell, [6, 4]);
+    }
+
+    #[test]
+    fn hits_see_taller_walls_over_nearer_ones() {
+        let mut map = GameMap::bordered(10, 10);
+        map[(6, 4)] = 1;
+        map.set_wall_height(9, 4, 2.0);
+        let (passed, stop) = east(&map).hits(&map, 0.5, 10.0);
+        let passed: Vec<_> = passed.iter().map(|hit| hit.cell).collect();
+        assert_eq!(passed, [[6, 4]]);
+        assert_eq!(stop.unwrap().cell, [9, 4]);
+
+        // A wall no taller than one in front that reaches the eye is hidden
+        map.set_wall_height(9, 4, 1.0);
+        map.set_wall_height(0, 8, 2.0);
+        let (passed, stop) = east(&map).hits(&map, 0.5, 10.0);
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].cell, [6, 4]);
+        assert!(stop.is_none());
+    }
+
+    #[test]
+    fn hits_see_over_walls_below_the_eye() {
+        let mut map = GameMap::bordered(10, 10);
+        map[(6, 4)] = 1;
+        map.set_wall_height(6, 4, 0.25);
+        let (passed, stop) = east(&map).hits(&map, 0.5, 10.0);
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].cell, [6, 4]);
+        assert_eq!(stop.unwrap().cell, [9, 4]);
+    }
+
+    #[test]
+    fn axis_aligned_hits_are_the_distance_to_the_wall_face() {
+        let map = GameMap::bordered(10, 10);
+        // Cells are 0.2 wide, and the ray starts 3.5 cells from the faces of
+        // the west and south walls and 4.5 cells from the east and north ones
+        for (rotation, cell, face, distance) in [
+            (0.0, [4, 9], Face::South, 0.9),
+            (90.0, [0, 4], Face::East, 0.7),
+            (180.0, [4, 0], Face::North, 0.7),
+            (270.0, [9, 4], Face::West, 0.9),
+        ] {
+            let hit = ray_from(&map, [4.5, 4.5], rotation)
+                .collision(&map, 10.0)
+                .unwrap();
+            assert_eq!(hit.cell, cell);
+            assert_eq!(hit.face, face);
+            assert_near(hit.distance, distance);
+        }
+    }
+
+    #[test]
+    fn negative_angles_hit_like_their_positive_turns() {
+        let map = GameMap::bordered(10, 10);
+        for (negative, positive) in [(-90.0, 270.0), (-45.0, 315.0), (-135.0, 225.0)] {
+            let negative = ray_from(&map, [4.5, 2.2], negative).collision(&map, 10.0);
+            let positive = ray_from(&map, [4.5, 2.2], positive).collision(&map, 10.0);
+            let (negative, positive) = (negative.unwrap(), positive.unwrap());
+            assert_eq!(negative.cell, positive.cell);
+            assert_eq!(negative.face, positive.face);
+            assert_near(negative.distance, positive.distance);
+        }
+
+        // Heading north east, the ray reaches the east wall 4.5 cells across
+        let hit = ray_from(&map, [4.5, 2.2], -45.0)
+            .collision(&map, 10.0)
+            .unwrap();
+        assert_eq!(hit.cell, [9, 6]);
+        assert_eq!(hit.face, Face::West);
+        assert_near(hit.distance, 4.5 * 2.0_f32.sqrt() * 0.2);
+    }
+
+    #[test]
+    fn rays_on_an_open_map_miss() {
+        let map = GameMap::new(10, 10);
+        for rotation in [0.0, 45.0, 90.0, 200.0, 270.0, 333.0] {
+            assert!(ray_from(&map, [4.5, 4.5], rotation)
+                .collision(&map, 10.0)
+                .is_none());
+        }
+        // Nor does a ray from outside the map heading away from it hit anything
+        assert!(ray_from(&map, [-2.0, 4.5], 90.0)
+            .collision(&map, 10.0)
+            .is_none());
+    }
+
+    #[test]
+    fn walls_beyond_the_max_distance_are_missed() {
+        let map = GameMap::bordered(10, 10);
+        let ray = ray_from(&map, [4.5, 4.5], 270.0);
+        assert!(ray.collision(&map, 0.85).is_none());
+        assert!(ray.collision(&map, 0.95).is_some());
+        let (passed, stop) = ray.hits(&map, 0.5, 0.85);
+        assert!(passed.is_empty() && stop.is_none());
+    }
+
+    /// A bordered map split by a wall along row 6, with a door in it at (5, 6).
+    fn door_map() -> GameMap {
+        let mut map = GameMap::bordered(10, 10);
+        for x in 1..9 {
+            map[(x, 6)] = 1;
         }
-        (final_length, lighting)
+        map[(5, 6)] = DOOR_TILE;
+        map
+    }
+
+    #[test]
+    fn closed_doors_are_hit_across_the_middle_of_their_cell() {
+        let map = door_map();
+        let hit = ray_from(&map, [5.25, 2.5], 0.0)
+            .collision(&map, 10.0)
+            .unwrap();
+        assert_eq!(hit.cell, [5, 6]);
+        assert_eq!(hit.face, Face::South);
+        assert_near(hit.distance, 0.8);
+        assert_near(hit.tex_x, 0.25);
+    }
+
+    #[test]
+    fn rays_pass_the_open_part_of_a_door() {
+        let mut map = door_map();
+        map.toggle_door(5, 6);
+        map.update_doors(0.5);
+
+        let through = ray_from(&map, [5.25, 2.5], 0.0).collision(&map, 10.0);
+        assert_eq!(through.unwrap().cell, [5, 9]);
+        // The texture slides along with the door
+        let hit = ray_from(&map, [5.75, 2.5], 0.0)
+            .collision(&map, 10.0)
+            .unwrap();
+        assert_eq!(hit.cell, [5, 6]);
+        assert_near(hit.distance, 0.8);
+        assert_near(hit.tex_x, 0.25);
+    }
+
+    #[test]
+    fn sliding_push_walls_are_hit_where_they_have_got_to() {
+        let mut map = GameMap::bordered(10, 10);
+        map[(3, 5)] = PUSH_WALL_TILE;
+        assert!(map.push_wall(3, 5, [1, 0]));
+        map.update_push_walls(0.5);
+
+        // Halfway into the next cell, the wall's west face is at x = 3.5
+        let hit = ray_from(&map, [1.5, 5.5], 270.0)
+            .collision(&map, 10.0)
+            .unwrap();
+        assert_eq!(hit.face, Face::West);
+        assert_near(hit.distance, 0.4);
+
+        // Its south face is only hit beneath where it has slid to
+        let left_behind = ray_from(&map, [3.25, 1.5], 0.0).collision(&map, 10.0);
+        assert_eq!(left_behind.unwrap().cell, [3, 9]);
+        let hit = ray_from(&map, [4.25, 1.5], 0.0)
+            .collision(&map, 10.0)
+            .unwrap();
+        assert_eq!(hit.face, Face::South);
+        assert_near(hit.distance, 0.7);
     }
 }
@@ -0,0 +1,22 @@
+/// An object standing in the world, drawn as a billboard that always faces the camera.
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+    pub pos: [f32; 2],
+    /// Atlas slot of the sprite's texture.
+    pub texture: u32,
+    /// Size relative to a wall, 1.0 being as wide as a cell and as tall as a wall.
+    pub scale: f32,
+    /// Index of the storey the sprite stands on.
+    pub storey: usize,
+}
+
+impl Sprite {
+    pub fn new(pos: [f32; 2], texture: u32) -> Self {
+        Self {
+            pos,
+            texture,
+            scale: 1.0,
+            storey: 0,
+        }
+    }
+}
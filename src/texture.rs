@@ -0,0 +1,398 @@
+use crate::ray::Face;
+
+pub const TEXTURE_SIZE: u32 = 64;
+pub const ATLAS_COLUMNS: u32 = 8;
+
+/// Slot holding a plain white texture, used by untextured geometry so that
+/// everything can be drawn through the same pipeline.
+pub const BLANK_SLOT: u32 = 0;
+
+type Generator = fn(u32, u32) -> [u8; 4];
+
+/// Number of slots, starting from the blank slot, that hold tile textures.
+/// Sprite textures come after them.
+pub const TILE_SLOTS: u32 = 16;
+
+pub const BARREL_SLOT: u32 = 16;
+pub const PILLAR_SLOT: u32 = 17;
+pub const PLANT_SLOT: u32 = 18;
+
+/// Textures in the order they are laid out in the atlas. A tile's value is the
+/// slot of the texture it is drawn with.
+const GENERATORS: [Generator; 19] = [
+    blank,
+    red_brick,
+    grey_stone,
+    wood,
+    blue_tile,
+    mossy_stone,
+    metal,
+    purple_stone,
+    checker_floor,
+    ceiling_panel,
+    door,
+    // Push walls blend in with the brick walls around them
+    red_brick,
+    window,
+    grate,
+    fence,
+    // Only seen once a ray has bounced between mirrors as often as it may
+    mirror,
+    barrel,
+    pillar,
+    plant,
+];
+
+pub struct Atlas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Atlas {
+    pub fn generate() -> Self {
+        let rows = (GENERATORS.len() as u32).div_ceil(ATLAS_COLUMNS);
+        let width = ATLAS_COLUMNS * TEXTURE_SIZE;
+        let height = rows * TEXTURE_SIZE;
+        let mut pixels = vec![0; (width * height * 4) as usize];
+
+        for (slot, generator) in GENERATORS.iter().enumerate() {
+            let (slot_x, slot_y) = slot_origin(slot as u32);
+            for y in 0..TEXTURE_SIZE {
+                for x in 0..TEXTURE_SIZE {
+                    let i = (((slot_y + y) * width + slot_x + x) * 4) as usize;
+                    pixels[i..i + 4].copy_from_slice(&generator(x, y));
+                }
+            }
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+pub fn slot_count() -> u32 {
+    GENERATORS.len() as u32
+}
+
+/// The texture slot a tile is drawn with, wrapping around tiles that have no
+/// texture of their own.
+pub fn tile_slot(tile: u8) -> u32 {
+    match tile as u32 {
+        0 => BLANK_SLOT,
+        t => (t - 1) % (TILE_SLOTS - 1) + 1,
+    }
+}
+
+/// How brightly each face of a tile is drawn, as if the level were lit more
+/// from some sides than others, and the colour the whole tile is tinted by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceShades {
+    /// Brightness of the north, south, east and west faces.
+    pub brightness: [f32; 4],
+    pub tint: [f32; 3],
+}
+
+impl FaceShades {
+    const fn new(brightness: [f32; 4], tint: [f32; 3]) -> Self {
+        Self { brightness, tint }
+    }
+
+    /// The colour a face is drawn in.
+    pub fn shade(&self, face: Face) -> [f32; 3] {
+        let brightness = match face {
+            Face::North => self.brightness[0],
+            Face::South => self.brightness[1],
+            Face::East => self.brightness[2],
+            Face::West => self.brightness[3],
+        };
+        self.tint.map(|c| c * brightness)
+    }
+}
+
+const NEUTRAL: [f32; 3] = [1.0, 1.0, 1.0];
+const WARM: [f32; 3] = [1.0, 0.93, 0.86];
+const COOL: [f32; 3] = [0.92, 0.96, 1.0];
+
+/// Face shades of each tile texture, in the same order as `GENERATORS`. Rough
+/// and dark materials have more contrast between their faces than smooth and
+/// shiny ones.
+const FACE_SHADES: [FaceShades; TILE_SLOTS as usize] = [
+    FaceShades::new([1.0, 0.8, 0.7, 0.6], NEUTRAL),
+    FaceShades::new([1.0, 0.75, 0.85, 0.6], WARM),
+    FaceShades::new([0.95, 0.6, 0.8, 0.5], COOL),
+    FaceShades::new([1.0, 0.8, 0.9, 0.7], WARM),
+    FaceShades::new([1.0, 0.85, 0.9, 0.75], COOL),
+    FaceShades::new([0.9, 0.55, 0.75, 0.5], [0.92, 1.0, 0.9]),
+    FaceShades::new([1.0, 0.5, 0.9, 0.45], COOL),
+    FaceShades::new([0.95, 0.65, 0.8, 0.55], [1.0, 0.94, 1.0]),
+    FaceShades::new([1.0, 0.8, 0.9, 0.7], NEUTRAL),
+    FaceShades::new([1.0, 0.8, 0.9, 0.7], NEUTRAL),
+    FaceShades::new([1.0, 0.75, 0.85, 0.65], WARM),
+    // Push walls blend in with the brick walls around them
+    FaceShades::new([1.0, 0.75, 0.85, 0.6], WARM),
+    FaceShades::new([1.0, 0.85, 0.9, 0.8], NEUTRAL),
+    FaceShades::new([1.0, 0.6, 0.85, 0.5], NEUTRAL),
+    FaceShades::new([1.0, 0.75, 0.85, 0.65], WARM),
+    FaceShades::new([1.0, 0.9, 0.95, 0.85], NEUTRAL),
+];
+
+/// The face shades a tile is drawn with, wrapping around like its texture.
+pub fn face_shades(tile: u8) -> FaceShades {
+    FACE_SHADES[tile_slot(tile) as usize]
+}
+
+fn slot_origin(slot: u32) -> (u32, u32) {
+    (
+        (slot % ATLAS_COLUMNS) * TEXTURE_SIZE,
+        (slot / ATLAS_COLUMNS) * TEXTURE_SIZE,
+    )
+}
+
+/// Maps a coordinate inside a slot, both axes in 0.0..=1.0, to a coordinate in the atlas.
+pub fn atlas_uv(slot: u32, u: f32, v: f32) -> [f32; 2] {
+    let rows = slot_count().div_ceil(ATLAS_COLUMNS);
+    let (slot_x, slot_y) = slot_origin(slot);
+    // Keep clear of the slot edges so nearest sampling never bleeds into a neighbour
+    let inset = 0.5 / TEXTURE_SIZE as f32;
+    let u = u.clamp(inset, 1.0 - inset);
+    let v = v.clamp(inset, 1.0 - inset);
+    [
+        (slot_x as f32 + u * TEXTURE_SIZE as f32) / (ATLAS_COLUMNS * TEXTURE_SIZE) as f32,
+        (slot_y as f32 + v * TEXTURE_SIZE as f32) / (rows * TEXTURE_SIZE) as f32,
+    ]
+}
+
+/// Cheap integer hash giving a stable value in 0.0..1.0 for each texel.
+fn noise(x: u32, y: u32, seed: u32) -> f32 {
+    let mut h = x
+        .wrapping_mul(374_761_393)
+        .wrapping_add(y.wrapping_mul(668_265_263))
+        .wrapping_add(seed.wrapping_mul(2_246_822_519));
+    h = (h ^ (h >> 13)).wrapping_mul(1_274_126_177);
+    h ^= h >> 16;
+    (h & 0xffff) as f32 / 65536.0
+}
+
+fn shade(color: [u8; 3], amount: f32) -> [u8; 4] {
+    let s = |c: u8| (c as f32 * amount).clamp(0.0, 255.0) as u8;
+    [s(color[0]), s(color[1]), s(color[2]), 255]
+}
+
+fn blank(_x: u32, _y: u32) -> [u8; 4] {
+    [255, 255, 255, 255]
+}
+
+fn bricks(x: u32, y: u32, brick: [u8; 3], mortar: [u8; 3], seed: u32) -> [u8; 4] {
+    let (brick_w, brick_h) = (16, 8);
+    let row = y / brick_h;
+    let offset = if row.is_multiple_of(2) {
+        0
+    } else {
+        brick_w / 2
+    };
+    let bx = (x + offset) % brick_w;
+    if y.is_multiple_of(brick_h) || bx == 0 {
+        return shade(mortar, 0.9 + noise(x, y, seed) * 0.2);
+    }
+    let brick_id = (x + offset) / brick_w;
+    let tone = 0.75 + noise(brick_id, row, seed) * 0.25 + noise(x, y, seed + 1) * 0.1;
+    shade(brick, tone)
+}
+
+fn red_brick(x: u32, y: u32) -> [u8; 4] {
+    bricks(x, y, [170, 50, 40], [190, 180, 170], 1)
+}
+
+fn grey_stone(x: u32, y: u32) -> [u8; 4] {
+    let (block_w, block_h) = (32, 16);
+    let row = y / block_h;
+    let offset = if row.is_multiple_of(2) {
+        0
+    } else {
+        block_w / 2
+    };
+    let bx = (x + offset) % block_w;
+    if y.is_multiple_of(block_h) || bx == 0 {
+        return shade([60, 60, 60], 1.0);
+    }
+    let tone = 0.7 + noise(x / 2, y / 2, 2) * 0.3;
+    shade([150, 150, 155], tone)
+}
+
+fn wood(x: u32, y: u32) -> [u8; 4] {
+    let plank = x / 16;
+    if x.is_multiple_of(16) {
+        return shade([70, 40, 15], 1.0);
+    }
+    let grain = ((y as f32 / 3.0 + noise(plank, 0, 3) * 10.0).sin() + 1.0) / 2.0;
+    shade([150, 95, 45], 0.75 + grain * 0.2 + noise(x, y, 3) * 0.1)
+}
+
+fn blue_tile(x: u32, y: u32) -> [u8; 4] {
+    if x.is_multiple_of(16) || y.is_multiple_of(16) {
+        return shade([200, 200, 210], 1.0);
+    }
+    shade([40, 70, 170], 0.85 + noise(x, y, 4) * 0.15)
+}
+
+fn mossy_stone(x: u32, y: u32) -> [u8; 4] {
+    let stone = grey_stone(x, y);
+    if noise(x / 4, y / 4, 5) > 0.6 {
+        let moss = 0.7 + noise(x, y, 6) * 0.3;
+        return shade([60, 130, 50], moss);
+    }
+    stone
+}
+
+fn metal(x: u32, y: u32) -> [u8; 4] {
+    let (px, py) = (x % 32, y % 32);
+    if px == 0 || py == 0 {
+        return shade([40, 45, 50], 1.0);
+    }
+    if (px == 4 || px == 28) && (py == 4 || py == 28) {
+        return shade([220, 220, 220], 1.0);
+    }
+    shade([110, 120, 130], 0.9 + noise(x, y, 7) * 0.1)
+}
+
+fn purple_stone(x: u32, y: u32) -> [u8; 4] {
+    bricks(x, y, [110, 60, 140], [50, 30, 60], 8)
+}
+
+fn checker_floor(x: u32, y: u32) -> [u8; 4] {
+    let light = (x / 32 + y / 32).is_multiple_of(2);
+    let color = if light { [150, 150, 140] } else { [80, 80, 85] };
+    shade(color, 0.9 + noise(x, y, 9) * 0.1)
+}
+
+fn ceiling_panel(x: u32, y: u32) -> [u8; 4] {
+    let (px, py) = (x % 32, y % 32);
+    if px == 0 || py == 0 {
+        return shade([120, 110, 90], 1.0);
+    }
+    shade([200, 190, 165], 0.92 + noise(x, y, 10) * 0.08)
+}
+
+fn door(x: u32, y: u32) -> [u8; 4] {
+    let edge = 2..TEXTURE_SIZE - 2;
+    if !edge.contains(&x) || !edge.contains(&y) {
+        return shade([50, 55, 60], 1.0);
+    }
+    if (50..54).contains(&x) && (28..36).contains(&y) {
+        return shade([230, 200, 80], 1.0);
+    }
+    let panel = if (8..56).contains(&y) && matches!(x % 16, 0 | 15) {
+        0.7
+    } else {
+        1.0
+    };
+    shade([80, 120, 150], panel * (0.9 + noise(x, y, 16) * 0.1))
+}
+
+fn window(x: u32, y: u32) -> [u8; 4] {
+    let frame = !(4..TEXTURE_SIZE - 4).contains(&x)
+        || !(4..TEXTURE_SIZE - 4).contains(&y)
+        || (30..34).contains(&x)
+        || (30..34).contains(&y);
+    if frame {
+        return shade([120, 80, 40], 0.9 + noise(x, y, 17) * 0.1);
+    }
+    // Faint streaks on the glass
+    let streak = if (x + y) % 24 < 3 { 40 } else { 0 };
+    [170 + streak, 210 + streak / 2, 230, 90 + streak]
+}
+
+fn mirror(x: u32, y: u32) -> [u8; 4] {
+    let frame = !(3..TEXTURE_SIZE - 3).contains(&x) || !(3..TEXTURE_SIZE - 3).contains(&y);
+    if frame {
+        return shade([150, 120, 60], 0.9 + noise(x, y, 21) * 0.1);
+    }
+    // A bright sheen running diagonally across the silvered glass
+    let sheen = if (x + TEXTURE_SIZE - y) % 32 < 6 {
+        1.2
+    } else {
+        1.0
+    };
+    shade([160, 175, 185], sheen)
+}
+
+fn grate(x: u32, y: u32) -> [u8; 4] {
+    let rail = !(4..TEXTURE_SIZE - 4).contains(&y);
+    let bar = x % 10 < 3;
+    if rail || bar {
+        let curve = if rail {
+            1.0
+        } else {
+            0.8 + (x % 10) as f32 * 0.1
+        };
+        return shade([90, 95, 100], curve * (0.85 + noise(x, y, 18) * 0.15));
+    }
+    CLEAR
+}
+
+fn fence(x: u32, y: u32) -> [u8; 4] {
+    let picket = x % 16;
+    // Pickets have pointed tops and gaps between them
+    let point = 8 + picket.abs_diff(6) * 2;
+    let is_picket = picket < 12 && y >= point;
+    let is_rail = (22..26).contains(&y) || (48..52).contains(&y);
+    if is_picket || is_rail {
+        let tone = if is_picket { 1.0 } else { 0.85 };
+        return shade([205, 195, 170], tone * (0.9 + noise(x, y, 19) * 0.1));
+    }
+    CLEAR
+}
+
+const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+/// Distance of a texel centre from the vertical centre line, in texels.
+fn from_centre(x: u32) -> f32 {
+    (x as f32 + 0.5 - TEXTURE_SIZE as f32 / 2.0).abs()
+}
+
+fn barrel(x: u32, y: u32) -> [u8; 4] {
+    // Bulges slightly in the middle and sits on the bottom of the texture
+    let bulge = 1.0 - ((y as f32 - 40.0) / 24.0).powi(2) * 0.15;
+    let half_width = 14.0 * bulge;
+    if y < 16 || from_centre(x) > half_width {
+        return CLEAR;
+    }
+    if (y - 16) % 16 < 2 {
+        return shade([90, 90, 95], 1.0);
+    }
+    let curve = 1.0 - (from_centre(x) / half_width).powi(2) * 0.5;
+    shade([150, 90, 40], curve * (0.85 + noise(x, y, 11) * 0.15))
+}
+
+fn pillar(x: u32, y: u32) -> [u8; 4] {
+    let capital = !(6..TEXTURE_SIZE - 6).contains(&y);
+    let half_width = if capital { 14.0 } else { 10.0 };
+    if from_centre(x) > half_width {
+        return CLEAR;
+    }
+    let curve = 1.0 - (from_centre(x) / half_width).powi(2) * 0.4;
+    shade([215, 210, 200], curve * (0.9 + noise(x, y, 12) * 0.1))
+}
+
+fn plant(x: u32, y: u32) -> [u8; 4] {
+    if y >= 48 {
+        // Pot
+        return if from_centre(x) <= 10.0 - (TEXTURE_SIZE - y) as f32 / 4.0 {
+            shade([160, 80, 50], 0.9 + noise(x, y, 13) * 0.1)
+        } else {
+            CLEAR
+        };
+    }
+    let dx = from_centre(x);
+    let dy = 30.0 - y as f32;
+    let leaf = dx * dx / 400.0 + dy * dy / 500.0 < 1.0 && noise(x / 3, y / 3, 14) > 0.3;
+    if leaf {
+        shade([50, 140, 60], 0.7 + noise(x, y, 15) * 0.3)
+    } else {
+        CLEAR
+    }
+}
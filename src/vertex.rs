@@ -3,6 +3,11 @@
 pub struct Vertex {
     pub position: [f32; 2],
     pub color: [f32; 3],
+    pub tex_coords: [f32; 2],
+    /// Distance from the camera, letting the GPU interpolate texture
+    /// coordinates with perspective and fog the 3D view. Geometry that isn't
+    /// part of the 3D view, like the map, uses 0.0.
+    pub depth: f32,
 }
 
 impl Vertex {
@@ -21,6 +26,16 @@ impl Vertex {
                     shader_location: 1,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32,
+                },
             ],
         }
     }
@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use crate::map::GameMap;
+use crate::ray::{map_rays, Ray};
+
+/// Whether `to` can be seen from `from`, both in world space: nothing that
+/// would stop a ray, such as a wall or a closed door, lies between them.
+/// See-through tiles do not block it, but every wall does, however short.
+pub fn line_of_sight(map: &GameMap, from: [f32; 2], to: [f32; 2]) -> bool {
+    let ray = Ray::towards(from, to);
+    if ray.length == 0.0 {
+        return true;
+    }
+    ray.collision(map, ray.length).is_none()
+}
+
+/// The cells that can be seen from `origin`, looking along `rotation` with a
+/// field of view `fov` degrees across, out to `max_distance`. The walls that
+/// stop the view are included, as their faces are seen. A `fov` of 360 or
+/// more looks all the way round.
+pub fn visible_cells(
+    map: &GameMap,
+    origin: [f32; 2],
+    rotation: f32,
+    fov: f32,
+    max_distance: f32,
+) -> HashSet<[usize; 2]> {
+    let fov = fov.clamp(0.0, 360.0);
+    // Rays close enough together that no cell within reach slips between them.
+    let spacing = (map.cell_width() / 2.0 / max_distance).atan().to_degrees();
+    let count = (fov / spacing).ceil().max(1.0) as usize;
+    let rays: Vec<Ray> = (0..=count)
+        .map(|i| Ray {
+            origin,
+            length: 0.0,
+            rotation: rotation - fov / 2.0 + fov * i as f32 / count as f32,
+        })
+        .collect();
+
+    let cells = map_rays(&rays, |ray| {
+        let stop = ray.collision(map, max_distance);
+        let reach = stop.as_ref().map_or(max_distance, |hit| hit.distance);
+        // No wall is seen over, as the view ends at the first one
+        let mut cells: Vec<[usize; 2]> = ray
+            .spans(map, f32::INFINITY, reach)
+            .iter()
+            .map(|span| span.cell)
+            .collect();
+        cells.extend(stop.map(|hit| hit.cell));
+        cells
+    });
+    cells.into_iter().flatten().collect()
+}
+
+/// Whether `target` is within `max_distance` of `origin`, inside the field of
+/// view looking along `rotation`, and in line of sight.
+pub fn can_see(
+    map: &GameMap,
+    origin: [f32; 2],
+    rotation: f32,
+    fov: f32,
+    max_distance: f32,
+    target: [f32; 2],
+) -> bool {
+    let ray = Ray::towards(origin, target);
+    let offset = (ray.rotation - rotation + 180.0).rem_euclid(360.0) - 180.0;
+    ray.length <= max_distance && offset.abs() <= fov / 2.0 && line_of_sight(map, origin, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bordered map with a wall in the middle of row 4.
+    fn walled() -> GameMap {
+        let mut map = GameMap::bordered(10, 10);
+        map[(5, 4)] = 1;
+        map
+    }
+
+    #[test]
+    fn walls_block_the_line_of_sight() {
+        let map = walled();
+        assert!(!line_of_sight(
+            &map,
+            map.cell_center(2, 4),
+            map.cell_center(7, 4)
+        ));
+        assert!(line_of_sight(
+            &map,
+            map.cell_center(2, 4),
+            map.cell_center(4, 4)
+        ));
+    }
+
+    #[test]
+    fn open_lines_can_be_seen_along() {
+        let map = walled();
+        assert!(line_of_sight(
+            &map,
+            map.cell_center(2, 2),
+            map.cell_center(7, 8)
+        ));
+        assert!(line_of_sight(
+            &map,
+            map.cell_center(2, 6),
+            map.cell_center(7, 6)
+        ));
+    }
+
+    #[test]
+    fn a_tall_wall_does_not_let_sight_through_the_others() {
+        let mut map = walled();
+        map.set_wall_height(0, 8, 2.0);
+        assert!(!line_of_sight(
+            &map,
+            map.cell_center(2, 4),
+            map.cell_center(7, 4)
+        ));
+
+        let cells = visible_cells(&map, map.cell_center(2, 4), 270.0, 10.0, 2.0);
+        assert!(cells.contains(&[5, 4]));
+        assert!(!cells.contains(&[6, 4]));
+    }
+
+    #[test]
+    fn the_edge_of_the_field_of_view_is_inside_it() {
+        let map = walled();
+        let (origin, target) = (map.cell_center(2, 2), map.cell_center(2, 6));
+        // Looking east, straight north is on the edge of a half turn
+        assert!(can_see(&map, origin, 270.0, 180.0, 2.0, target));
+        assert!(!can_see(&map, origin, 270.0, 179.0, 2.0, target));
+        assert!(!can_see(&map, origin, 270.0, 180.0, 0.5, target));
+
+        let cells = visible_cells(&map, origin, 270.0, 90.0, 2.0);
+        assert!(cells.contains(&[4, 2]));
+        assert!(!cells.contains(&[2, 4]));
+    }
+}
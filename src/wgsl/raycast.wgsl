@@ -0,0 +1,852 @@
+// Draws the 3D view by casting a ray through every pixel column, following
+// the same steps as the CPU raycaster in ray.rs so the two draw the same image.
+// Rays carry on through portals and bounce off mirrors, drawing what they see
+// beyond them as though it were further along the ray. Everything is lit by
+// the same light samples the CPU path reads.
+// Every floor, wall and sprite the ray passes that covers the pixel is kept,
+// and the nearest solid one is drawn with any see-through ones in front of it
+// blended over it, as drawing them furthest first on the CPU does.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // Position in the 3D view, both axes from -1.0 to 1.0
+    @location(0) screen: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // Two triangles covering the right half of the window
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(-1.0, 1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+    );
+    let corner = corners[index];
+    var out: VertexOutput;
+    out.screen = corner;
+    out.clip_position = vec4<f32>((corner.x + 1.0) / 2.0, corner.y, 0.0, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var t_atlas: texture_2d<f32>;
+@group(0) @binding(1)
+var s_atlas: sampler;
+
+struct Fog {
+    color: vec3<f32>,
+    density: f32,
+    mode: u32,
+};
+
+@group(1) @binding(0)
+var<uniform> fog: Fog;
+
+struct View {
+    // Camera position in world space
+    position: vec2<f32>,
+    direction: vec2<f32>,
+    plane: vec2<f32>,
+    // Height of the horizon on the screen and of the eye above the floor of
+    // the player's storey
+    horizon: f32,
+    eye: f32,
+    max_distance: f32,
+    wall_height: f32,
+    columns: f32,
+    storey_count: u32,
+    ground: vec4<f32>,
+    sky: vec4<f32>,
+    lit: vec4<f32>,
+    // What each bounce off a mirror tints the colour of anything seen in it by
+    mirror_tint: vec4<f32>,
+    atlas: vec4<f32>,
+    // Two per storey: its size, first row in the map texture and cell width,
+    // then its tallest wall, how far its floor is above the player's and how
+    // many pieces its floors and ceilings are lit in
+    storeys: array<vec4<f32>, 8>,
+    // Push walls, segment walls, sprites and portals each give their storey last
+    push_walls: array<vec4<f32>, 16>,
+    segments: array<vec4<f32>, 64>,
+    // Two per sprite: its world position, slot and scale, then its colour
+    sprites: array<vec4<f32>, 32>,
+    // Two per portal: the cell and face it is on, then the cell and face it
+    // leads to
+    portals: array<vec4<f32>, 32>,
+    // Four per tile texture slot, indexed by face
+    face_shades: array<vec4<f32>, 64>,
+    push_wall_count: u32,
+    segment_count: u32,
+    sprite_count: u32,
+    portal_count: u32,
+    // How many portals a ray goes through and mirrors it bounces off before
+    // it gives up
+    max_portals: u32,
+    max_bounces: u32,
+    // Light samples across each cell and along each of its faces
+    light_samples: u32,
+};
+
+@group(2) @binding(0)
+var t_map: texture_2d<u32>;
+@group(2) @binding(1)
+var<uniform> view: View;
+// A block of light samples per cell, laid out like the map texture, with the
+// floor samples in the middle of each block and the samples along each face
+// on its edge on that side
+@group(2) @binding(2)
+var t_light: texture_2d<f32>;
+
+const EMPTY: u32 = 0u;
+const WALL: u32 = 1u;
+const SEE_THROUGH: u32 = 2u;
+const DOOR_ALONG_X: u32 = 3u;
+const DOOR_ALONG_Y: u32 = 4u;
+const MIRROR: u32 = 5u;
+
+const WEST: u32 = 0u;
+const EAST: u32 = 1u;
+const SOUTH: u32 = 2u;
+const NORTH: u32 = 3u;
+
+// Never walk further than this many cells, whatever the view distance
+const MAX_STEPS: i32 = 4096;
+// A tile that is not any of the map's, for cells outside it
+const NO_TILE: u32 = 0xffffffffu;
+
+struct Hit {
+    found: bool,
+    // Distance along the ray in map units, which is also the distance along
+    // the camera's direction because the ray is not normalised
+    t: f32,
+    face: u32,
+    tex_x: f32,
+    slot: u32,
+    // Wall height in standard wall heights
+    height: f32,
+};
+
+fn no_hit() -> Hit {
+    return Hit(false, 0.0, 0u, 0.0, 0u, 1.0);
+}
+
+fn wall_hit(t: f32, face: u32, tex_x: f32, slot: u32) -> Hit {
+    return Hit(true, t, face, tex_x, slot, 1.0);
+}
+
+// A surface covering the pixel, already fogged
+struct Layer {
+    depth: f32,
+    color: vec4<f32>,
+};
+
+// How many see-through surfaces in front of the nearest solid one are blended
+const MAX_LAYERS: u32 = 8u;
+
+var<private> layers: array<Layer, MAX_LAYERS>;
+var<private> layer_count: u32;
+// The nearest solid surface covering the pixel so far
+var<private> nearest: Layer;
+
+fn fog_amount(depth: f32) -> f32 {
+    if fog.mode == 0u {
+        return 0.0;
+    }
+    if fog.mode == 1u {
+        return clamp(depth * fog.density, 0.0, 1.0);
+    }
+    return 1.0 - exp(-depth * fog.density);
+}
+
+// Keeps a surface covering the pixel at perpendicular distance `depth`, unless
+// it is clear there or behind the nearest solid surface
+fn add_surface(depth: f32, color: vec4<f32>) {
+    if color.a < 0.01 || depth >= nearest.depth {
+        return;
+    }
+    let layer = Layer(depth, vec4<f32>(mix(color.rgb, fog.color, fog_amount(depth)), color.a));
+    if color.a >= 1.0 {
+        nearest = layer;
+    } else if layer_count < MAX_LAYERS {
+        layers[layer_count] = layer;
+        layer_count++;
+    }
+}
+
+// The nearest solid surface, or the backdrop if there is none, with the
+// see-through surfaces in front of it blended over it furthest first
+fn composite(y: f32) -> vec4<f32> {
+    var color = nearest.color.rgb;
+    if nearest.depth >= 1e30 {
+        color = select(view.ground.rgb, view.sky.rgb, y > 0.0);
+    }
+    var behind = nearest.depth;
+    for (var i = 0u; i < layer_count; i++) {
+        // The furthest layer nearer than the last one blended
+        var furthest = -1;
+        for (var j = 0u; j < layer_count; j++) {
+            let depth = layers[j].depth;
+            if depth < behind && (furthest < 0 || depth > layers[furthest].depth) {
+                furthest = i32(j);
+            }
+        }
+        if furthest < 0 {
+            break;
+        }
+        let layer = layers[furthest];
+        color = mix(color, layer.color.rgb, layer.color.a);
+        behind = layer.depth;
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+fn atlas_uv(slot: u32, u: f32, v: f32) -> vec2<f32> {
+    let columns = view.atlas.x;
+    let rows = view.atlas.y;
+    let texture_size = vec2<f32>(textureDimensions(t_atlas)) / vec2<f32>(columns, rows);
+    let inset = vec2<f32>(0.5) / texture_size;
+    let uv = clamp(vec2<f32>(u, v), inset, vec2<f32>(1.0) - inset);
+    let origin = vec2<f32>(f32(slot % u32(columns)), f32(slot / u32(columns)));
+    return (origin + uv) / vec2<f32>(columns, rows);
+}
+
+fn sample(slot: u32, u: f32, v: f32) -> vec4<f32> {
+    return textureSampleLevel(t_atlas, s_atlas, atlas_uv(slot, u, v), 0.0);
+}
+
+// One map of the level, whose texels are stacked above the storey below's
+struct Storey {
+    index: u32,
+    size: vec2<i32>,
+    row: i32,
+    cell_width: f32,
+    // Only walls this tall stop rays
+    tallest: f32,
+    // How far the floor is above the floor of the player's storey
+    offset: f32,
+    // How many pieces a floor or ceiling is lit in along a ray, each lit at
+    // its middle
+    light_pieces: f32,
+};
+
+fn storey_at(index: u32) -> Storey {
+    let first = view.storeys[index * 2u];
+    let second = view.storeys[index * 2u + 1u];
+    return Storey(index, vec2<i32>(first.xy), i32(first.z), first.w, second.x, second.y, second.z);
+}
+
+fn texel(storey: Storey, cell: vec2<i32>) -> vec4<u32> {
+    return textureLoad(t_map, cell + vec2<i32>(0, storey.row), 0);
+}
+
+fn in_map(storey: Storey, cell: vec2<i32>) -> bool {
+    return all(cell >= vec2<i32>(0)) && all(cell < storey.size);
+}
+
+// Whether a push wall, segment wall, sprite or portal, whose storey is given
+// last in the entry `entry`, is on a storey
+fn on_storey(entry: vec4<f32>, storey: Storey) -> bool {
+    return u32(entry.w) == storey.index;
+}
+
+// One stretch of a ray across a storey, from where it set out, came out of a
+// portal or bounced off a mirror
+struct Leg {
+    storey: Storey,
+    // Where the leg sets out from in map space
+    origin: vec2<f32>,
+    // Not normalised, so that distances along it are also distances along
+    // the camera's direction
+    ray: vec2<f32>,
+    // Perpendicular distance the ray covered before the leg
+    start: f32,
+    // The tint of the mirrors the ray bounced off before the leg
+    tint: vec3<f32>,
+};
+
+// Where a leg ended, and the leg the ray carries on along if it went into a
+// portal or mirror there
+struct LegEnd {
+    depth: f32,
+    carries_on: bool,
+    next: Leg,
+};
+
+// Perpendicular distance to a point `t` map units along a leg
+fn depth_at(leg: Leg, t: f32) -> f32 {
+    return leg.start + t * leg.storey.cell_width;
+}
+
+// How far along a leg a point at perpendicular distance `depth` is
+fn t_at(leg: Leg, depth: f32) -> f32 {
+    return (depth - leg.start) / leg.storey.cell_width;
+}
+
+fn point_at(leg: Leg, t: f32) -> vec2<f32> {
+    return leg.origin + leg.ray * t;
+}
+
+// How far a point on a face is moved off it to tell which side of the wall
+// it is on, in cells, as in light.rs
+const SURFACE_OFFSET: f32 = 1e-3;
+
+// One sample of the block of light samples of a cell
+fn light_texel(storey: Storey, cell: vec2<i32>, sample: vec2<i32>) -> vec3<f32> {
+    let block = i32(view.light_samples) + 2;
+    return textureLoad(t_light, (cell + vec2<i32>(0, storey.row)) * block + sample, 0).rgb;
+}
+
+// The sample containing a position across a cell, from 0.0 to 1.0
+fn light_sample(v: f32) -> i32 {
+    let samples = i32(view.light_samples);
+    return clamp(i32(floor(v * f32(samples))), 0, samples - 1);
+}
+
+// The light on the floor at a point in map space, like LightGrid::at
+fn floor_light(storey: Storey, point: vec2<f32>) -> vec3<f32> {
+    let cell = clamp(vec2<i32>(floor(point)), vec2<i32>(0), storey.size - vec2<i32>(1));
+    let within = point - vec2<f32>(cell);
+    return light_texel(storey, cell, vec2<i32>(1 + light_sample(within.x), 1 + light_sample(within.y)));
+}
+
+// The light on a face at a point in map space, like Lightmap::at. Faces that
+// are not on the grid are lit like the floor just in front of them.
+fn face_light(storey: Storey, point: vec2<f32>, face: u32) -> vec3<f32> {
+    let normal = quarter_turn(vec2<f32>(1.0, 0.0), face_turns(face));
+    let inside = point - normal * SURFACE_OFFSET;
+    let cell = clamp(vec2<i32>(floor(inside)), vec2<i32>(0), storey.size - vec2<i32>(1));
+    let offset = point - face_centre(vec2<f32>(cell), face);
+    if abs(dot(offset, normal)) > SURFACE_OFFSET {
+        return floor_light(storey, point + normal * SURFACE_OFFSET);
+    }
+    let along = 1 + light_sample(dot(offset, abs(normal.yx)) + 0.5);
+    let edge = select(0, i32(view.light_samples) + 1, normal.x + normal.y > 0.0);
+    if normal.x != 0.0 {
+        return light_texel(storey, cell, vec2<i32>(edge, along));
+    }
+    return light_texel(storey, cell, vec2<i32>(along, edge));
+}
+
+// Texture coordinate running left to right as seen from outside a face
+fn face_tex_x(face: u32, along: f32) -> f32 {
+    if face == WEST || face == NORTH {
+        return 1.0 - along;
+    }
+    return along;
+}
+
+fn door_hit(leg: Leg, cell: vec2<i32>, kind: u32, open: f32) -> Hit {
+    let origin = leg.origin;
+    let ray = leg.ray;
+    var fixed_axis = 0;
+    var along_axis = 1;
+    if kind == DOOR_ALONG_X {
+        fixed_axis = 1;
+        along_axis = 0;
+    }
+    if ray[fixed_axis] == 0.0 {
+        return no_hit();
+    }
+    let t = (f32(cell[fixed_axis]) + 0.5 - origin[fixed_axis]) / ray[fixed_axis];
+    let position = origin[along_axis] + ray[along_axis] * t - f32(cell[along_axis]);
+    if t < 0.0 || position < open || position > 1.0 {
+        return no_hit();
+    }
+    var face = WEST;
+    if fixed_axis == 0 && ray[fixed_axis] <= 0.0 {
+        face = EAST;
+    } else if fixed_axis == 1 && ray[fixed_axis] > 0.0 {
+        face = SOUTH;
+    } else if fixed_axis == 1 {
+        face = NORTH;
+    }
+    let slot = texel(leg.storey, cell).r >> 8u;
+    return wall_hit(t, face, position - open, slot);
+}
+
+fn push_wall_hit(leg: Leg, index: u32) -> Hit {
+    let origin = leg.origin;
+    let ray = leg.ray;
+    let cell = view.push_walls[index * 2u].xy;
+    let direction = view.push_walls[index * 2u].zw;
+    let offset = view.push_walls[index * 2u + 1u].x;
+    let slot = u32(view.push_walls[index * 2u + 1u].y);
+    let min_corner = cell + direction * offset;
+    let max_corner = min_corner + vec2<f32>(1.0);
+
+    var entry = vec2<f32>(-1e30);
+    var exit = vec2<f32>(1e30);
+    for (var axis = 0; axis < 2; axis++) {
+        if ray[axis] == 0.0 {
+            if origin[axis] < min_corner[axis] || origin[axis] > max_corner[axis] {
+                return no_hit();
+            }
+            continue;
+        }
+        let near = (min_corner[axis] - origin[axis]) / ray[axis];
+        let far = (max_corner[axis] - origin[axis]) / ray[axis];
+        entry[axis] = min(near, far);
+        exit[axis] = max(near, far);
+    }
+    let t = max(entry.x, entry.y);
+    if t > min(exit.x, exit.y) || t < 0.0 {
+        return no_hit();
+    }
+
+    var face = SOUTH;
+    var along = origin.x + ray.x * t - min_corner.x;
+    if entry.x > entry.y {
+        along = origin.y + ray.y * t - min_corner.y;
+        face = select(EAST, WEST, ray.x > 0.0);
+    } else if ray.y <= 0.0 {
+        face = NORTH;
+    }
+    return wall_hit(t, face, face_tex_x(face, along), slot);
+}
+
+fn occupies(index: u32, cell: vec2<i32>) -> bool {
+    let wall_cell = vec2<i32>(view.push_walls[index * 2u].xy);
+    let direction = vec2<i32>(view.push_walls[index * 2u].zw);
+    return all(cell == wall_cell) || all(cell == wall_cell + direction);
+}
+
+fn cross2(a: vec2<f32>, b: vec2<f32>) -> f32 {
+    return a.x * b.y - a.y * b.x;
+}
+
+fn segment_hit(leg: Leg, index: u32) -> Hit {
+    let origin = leg.origin;
+    let ray = leg.ray;
+    let start = view.segments[index * 2u].xy;
+    let along = view.segments[index * 2u].zw - start;
+    let slot = u32(view.segments[index * 2u + 1u].x);
+    let denominator = cross2(ray, along);
+    if denominator == 0.0 {
+        return no_hit();
+    }
+    let rel = start - origin;
+    let t = cross2(rel, along) / denominator;
+    let position = cross2(rel, ray) / denominator;
+    if t < 0.0 || position < 0.0 || position > 1.0 {
+        return no_hit();
+    }
+
+    var normal = vec2<f32>(-along.y, along.x);
+    if dot(normal, ray) > 0.0 {
+        normal = -normal;
+    }
+    var face: u32;
+    if abs(normal.x) > abs(normal.y) {
+        face = select(WEST, EAST, normal.x > 0.0);
+    } else {
+        face = select(SOUTH, NORTH, normal.y > 0.0);
+    }
+    let right = vec2<f32>(-normal.y, normal.x);
+    var tex_x = fract((1.0 - position) * length(along));
+    if dot(along, right) >= 0.0 {
+        tex_x = fract(position * length(along));
+    }
+    return wall_hit(t, face, tex_x, slot);
+}
+
+// Keeps the face of a hit if the pixel at height y is on it. The texture
+// repeats once per standard wall height, counted up from the floor so that
+// the top of each repeat is at v = 0.
+fn add_wall(leg: Leg, hit: Hit, y: f32, eye: f32) {
+    let depth = depth_at(leg, hit.t);
+    let on_wall = y * depth / view.wall_height + eye;
+    if on_wall < 0.0 || on_wall > hit.height {
+        return;
+    }
+    let repeat = min(floor(on_wall), ceil(hit.height) - 1.0);
+    let v = 1.0 - (on_wall - repeat);
+    let shade = view.face_shades[hit.slot * 4u + hit.face];
+    let light = face_light(leg.storey, point_at(leg, hit.t), hit.face);
+    let color = vec4<f32>(leg.tint * light, 1.0) * shade;
+    add_surface(depth, color * sample(hit.slot, hit.tex_x, v));
+}
+
+// Keeps the top of a wall lower than the eye if the pixel at height y is on
+// it, between where the ray enters the wall's cell and where it leaves
+fn add_top(leg: Leg, hit: Hit, exit: f32, cell: vec2<i32>, y: f32, eye: f32) {
+    if hit.height >= eye || y >= 0.0 {
+        return;
+    }
+    let drop = (eye - hit.height) * view.wall_height;
+    let depth = drop / -y;
+    let t = t_at(leg, depth);
+    if t < hit.t || t > exit {
+        return;
+    }
+    let within_cell = clamp(point_at(leg, t) - vec2<f32>(cell), vec2<f32>(0.0), vec2<f32>(1.0));
+    // Lit as a whole at the middle of the part that is on the screen
+    let near = max(depth_at(leg, hit.t), drop / (1.0 + view.horizon));
+    let middle = t_at(leg, (near + depth_at(leg, exit)) / 2.0);
+    let light = floor_light(leg.storey, point_at(leg, middle));
+    let color = vec4<f32>(leg.tint * light, 1.0) * view.lit;
+    add_surface(depth, color * sample(hit.slot, within_cell.x, 1.0 - within_cell.y));
+}
+
+// Keeps the floor or ceiling of a cell if the pixel at height y lands on it
+// between `entry` and `exit` along the ray
+fn add_planes(leg: Leg, cell: vec2<i32>, entry: f32, exit: f32, y: f32, eye: f32) {
+    // A plane `rise` above the eye is seen rise / d above the horizon at
+    // distance d, so the pixel sees the floor below the horizon and the
+    // ceiling above it
+    var rise = -eye;
+    var slot = texel(leg.storey, cell).g & 65535u;
+    if y > 0.0 {
+        rise = 1.0 - eye;
+        slot = texel(leg.storey, cell).g >> 16u;
+    }
+    if slot == 0u || rise * y <= 0.0 {
+        return;
+    }
+    let depth = rise * view.wall_height / y;
+    let t = t_at(leg, depth);
+    if t < entry || t > exit {
+        return;
+    }
+    let within_cell = point_at(leg, t) - vec2<f32>(cell);
+    // Lit in pieces along the part of the cell on the screen, each at its
+    // middle, as the CPU path draws it
+    var edge = 1.0 + view.horizon;
+    if y > 0.0 {
+        edge = 1.0 - view.horizon;
+    }
+    let near = max(depth_at(leg, entry), abs(rise) * view.wall_height / edge);
+    let pieces = leg.storey.light_pieces;
+    let piece_length = (depth_at(leg, exit) - near) / pieces;
+    let piece = clamp(floor((depth - near) / piece_length), 0.0, pieces - 1.0);
+    let middle = t_at(leg, near + piece_length * (piece + 0.5));
+    let light = floor_light(leg.storey, point_at(leg, middle));
+    let color = vec4<f32>(leg.tint * light, 1.0) * view.lit;
+    add_surface(depth, color * sample(slot, within_cell.x, 1.0 - within_cell.y));
+}
+
+// How many more portals the ray being cast can go through and mirrors it can
+// bounce off
+var<private> portals_left: u32;
+var<private> bounces_left: u32;
+
+// The portal on a face of a cell of a storey, or -1 if there is none or the
+// ray cannot go through any more
+fn portal_on(storey: Storey, cell: vec2<i32>, face: u32) -> i32 {
+    if portals_left == 0u {
+        return -1;
+    }
+    for (var i = 0u; i < view.portal_count; i++) {
+        let entry = view.portals[i * 2u];
+        if on_storey(entry, storey) && all(vec2<i32>(entry.xy) == cell) && u32(entry.z) == face {
+            return i32(i);
+        }
+    }
+    return -1;
+}
+
+// Quarter turns anticlockwise from east to the way a face points
+fn face_turns(face: u32) -> u32 {
+    var turns = array<u32, 4>(2u, 0u, 3u, 1u);
+    return turns[face];
+}
+
+// Turns a vector anticlockwise by a number of quarter turns, exactly
+fn quarter_turn(v: vec2<f32>, turns: u32) -> vec2<f32> {
+    switch turns {
+        case 1u: {
+            return vec2<f32>(-v.y, v.x);
+        }
+        case 2u: {
+            return -v;
+        }
+        case 3u: {
+            return vec2<f32>(v.y, -v.x);
+        }
+        default: {
+            return v;
+        }
+    }
+}
+
+// The middle of a face of a cell in map space
+fn face_centre(cell: vec2<f32>, face: u32) -> vec2<f32> {
+    return cell + vec2<f32>(0.5) + quarter_turn(vec2<f32>(0.5, 0.0), face_turns(face));
+}
+
+// Where a leg ends at a hit on a face of `cell`, carrying the ray on if the
+// face is a portal, like Portal::carry and Portal::turn, or a mirror, like
+// Ray::reflect. Portals are gone through whether or not the wall they are on
+// hides what is behind it.
+fn leave(leg: Leg, hit: Hit, cell: vec2<i32>) -> LegEnd {
+    let depth = depth_at(leg, hit.t);
+    let portal = portal_on(leg.storey, cell, hit.face);
+    if portal >= 0 {
+        let exit = view.portals[u32(portal) * 2u + 1u];
+        let face = u32(exit.z);
+        let turns = (face_turns(face) + 6u - face_turns(hit.face)) % 4u;
+        let point = point_at(leg, hit.t) - face_centre(vec2<f32>(cell), hit.face);
+        let origin = face_centre(exit.xy, face) + quarter_turn(point, turns);
+        let ray = quarter_turn(leg.ray, turns);
+        portals_left--;
+        return LegEnd(depth, true, Leg(storey_at(u32(exit.w)), origin, ray, depth, leg.tint));
+    }
+
+    // Only hits with the mirror's own tile are reflected
+    let data = texel(leg.storey, cell);
+    let mirror = in_map(leg.storey, cell) && (data.r & 255u) == MIRROR && hit.slot == data.r >> 8u;
+    if mirror && bounces_left > 0u {
+        var ray = leg.ray;
+        if hit.face == EAST || hit.face == WEST {
+            ray.x = -ray.x;
+        } else {
+            ray.y = -ray.y;
+        }
+        let tint = leg.tint * view.mirror_tint.rgb;
+        bounces_left--;
+        return LegEnd(depth, true, Leg(leg.storey, point_at(leg, hit.t), ray, depth, tint));
+    }
+    return LegEnd(depth, false, leg);
+}
+
+// Ends a leg at a door, push wall or segment wall, which is on a face of
+// `cell`, keeping it unless the ray carries on from there
+fn end_at(leg: Leg, hit: Hit, cell: vec2<i32>, y: f32, eye: f32) -> LegEnd {
+    let end = leave(leg, hit, cell);
+    if !end.carries_on {
+        add_wall(leg, hit, y, eye);
+    }
+    return end;
+}
+
+// Walks a leg across the grid like Ray::hits, keeping every wall it passes
+// that is not hidden behind a nearer one and the floor and ceiling of every
+// cell up to a wall that hides them, like Ray::spans. The leg ends where it
+// was stopped, went into a portal or mirror or ran out of distance.
+// Everything on the storey is seen from `eye` wall heights above its floor.
+fn walk(leg: Leg, y: f32, eye: f32) -> LegEnd {
+    let origin = leg.origin;
+    let ray = leg.ray;
+    let storey = leg.storey;
+    // The nearest segment wall ends the leg unless the grid stops it first
+    var end = t_at(leg, view.max_distance / length(ray));
+    var segment = no_hit();
+    for (var i = 0u; i < view.segment_count; i++) {
+        if !on_storey(view.segments[i * 2u + 1u], storey) {
+            continue;
+        }
+        let hit = segment_hit(leg, i);
+        if hit.found && hit.t <= end {
+            segment = hit;
+            end = hit.t;
+        }
+    }
+
+    var cell = vec2<i32>(floor(origin));
+    var step = vec2<i32>(0);
+    var delta = vec2<f32>(1e30);
+    var next = vec2<f32>(1e30);
+    for (var axis = 0; axis < 2; axis++) {
+        if ray[axis] == 0.0 {
+            continue;
+        }
+        delta[axis] = abs(1.0 / ray[axis]);
+        let floor_origin = floor(origin[axis]);
+        if ray[axis] > 0.0 {
+            step[axis] = 1;
+            next[axis] = (floor_origin + 1.0 - origin[axis]) * delta[axis];
+        } else {
+            step[axis] = -1;
+            next[axis] = (origin[axis] - floor_origin) * delta[axis];
+        }
+    }
+    let size = storey.size;
+    // The tallest wall so far that reaches up to the eye, hiding anything
+    // behind it that is no taller
+    var cover = -1e30;
+    var previous_tile = NO_TILE;
+    if in_map(storey, cell) {
+        previous_tile = texel(storey, cell).r >> 8u;
+    }
+    // Whether the floor and ceiling can still be seen over the walls passed
+    var open = true;
+    var entry = 0.0;
+
+    for (var i = 0; i < MAX_STEPS; i++) {
+        var axis = 1;
+        if next.x < next.y {
+            axis = 0;
+        }
+        let t = next[axis];
+        if open && in_map(storey, cell) {
+            add_planes(leg, cell, entry, min(t, end), y, eye);
+        }
+        if t > end {
+            break;
+        }
+        let left_x = (cell.x < 0 && step.x <= 0) || (cell.x >= size.x && step.x >= 0);
+        let left_y = (cell.y < 0 && step.y <= 0) || (cell.y >= size.y && step.y >= 0);
+        if left_x || left_y {
+            break;
+        }
+        cell[axis] += step[axis];
+        next[axis] += delta[axis];
+        entry = t;
+        if !in_map(storey, cell) {
+            previous_tile = NO_TILE;
+            continue;
+        }
+
+        // Doors and push walls stop the ray part way across their cell, past
+        // the floor and ceiling in front of them
+        for (var wall = 0u; wall < view.push_wall_count; wall++) {
+            if on_storey(view.push_walls[wall * 2u + 1u], storey) && occupies(wall, cell) {
+                let hit = push_wall_hit(leg, wall);
+                if hit.found && hit.t <= end {
+                    if open {
+                        add_planes(leg, cell, entry, hit.t, y, eye);
+                    }
+                    return end_at(leg, hit, vec2<i32>(view.push_walls[wall * 2u].xy), y, eye);
+                }
+            }
+        }
+
+        let data = texel(storey, cell);
+        let kind = data.r & 255u;
+        if kind == DOOR_ALONG_X || kind == DOOR_ALONG_Y {
+            let hit = door_hit(leg, cell, kind, bitcast<f32>(data.a));
+            if hit.found && hit.t <= end {
+                if open {
+                    add_planes(leg, cell, entry, hit.t, y, eye);
+                }
+                return end_at(leg, hit, cell, y, eye);
+            }
+            continue;
+        }
+
+        let slot = data.r >> 8u;
+        let height = bitcast<f32>(data.b);
+        var face: u32;
+        var along: f32;
+        let point = point_at(leg, t);
+        if axis == 0 {
+            face = select(EAST, WEST, step.x > 0);
+            along = fract(point.y);
+        } else {
+            face = select(NORTH, SOUTH, step.y > 0);
+            along = fract(point.x);
+        }
+        let hit = Hit(true, t, face, face_tex_x(face, along), slot, height);
+        if kind == SEE_THROUGH {
+            // Neighbouring see-through cells of the same kind are drawn as one
+            if slot != previous_tile && height > cover {
+                let end = leave(leg, hit, cell);
+                if end.carries_on {
+                    return end;
+                }
+                add_wall(leg, hit, y, eye);
+            }
+            previous_tile = slot;
+            continue;
+        }
+        previous_tile = slot;
+        if kind != WALL && kind != MIRROR {
+            continue;
+        }
+
+        let stops = height >= eye && height >= storey.tallest;
+        if stops || height > cover {
+            let end = leave(leg, hit, cell);
+            if end.carries_on {
+                return end;
+            }
+            add_wall(leg, hit, y, eye);
+            add_top(leg, hit, min(next.x, next.y), cell, y, eye);
+        }
+        if stops {
+            return LegEnd(depth_at(leg, t), false, leg);
+        }
+        if height >= eye {
+            cover = max(cover, height);
+        }
+        if height >= max(eye, 1.0) {
+            open = false;
+        }
+    }
+    if segment.found {
+        let point = point_at(leg, segment.t);
+        return end_at(leg, segment, vec2<i32>(max(floor(point), vec2<f32>(0.0))), y, eye);
+    }
+    return LegEnd(depth_at(leg, end), false, leg);
+}
+
+// Casts a ray from the camera across a storey and on through any portals it
+// goes into and mirrors it bounces off, like Ray::legs, returning the perpendicular distance at which
+// its first leg ended. Everything it passes is seen from `eye` wall heights
+// above the storey's floor, whichever storey it is on.
+fn cast_ray(storey: Storey, ray: vec2<f32>, y: f32, eye: f32) -> f32 {
+    portals_left = view.max_portals;
+    bounces_left = view.max_bounces;
+    let origin = (view.position + vec2<f32>(1.0)) / storey.cell_width;
+    var end = walk(Leg(storey, origin, ray, 0.0, vec3<f32>(1.0)), y, eye);
+    let first = end.depth;
+    while end.carries_on {
+        end = walk(end.next, y, eye);
+    }
+    return first;
+}
+
+// Keeps whichever sprite on a storey covers the pixel at `screen`, unless it
+// is as far as where the ray through its column was stopped on the storey, at
+// perpendicular distance `stop`
+fn add_sprites(storey: Storey, screen: vec2<f32>, stop: f32, eye: f32) {
+    let half_width = length(view.plane);
+    for (var i = 0u; i < view.sprite_count; i++) {
+        let sprite = view.sprites[i * 2u];
+        let color = view.sprites[i * 2u + 1u];
+        if !on_storey(color, storey) {
+            continue;
+        }
+        let rel = sprite.xy - view.position;
+        let depth = dot(view.direction, rel);
+        if depth <= 0.0 || depth >= stop {
+            continue;
+        }
+        let across = dot(view.plane, rel) / half_width;
+        let centre = across / (depth * half_width);
+        let scale = sprite.w;
+        let sprite_half_width = scale * storey.cell_width / 2.0 / (depth * half_width);
+        let u = (screen.x - (centre - sprite_half_width)) / (2.0 * sprite_half_width);
+        let bottom = view.horizon - eye * view.wall_height / depth;
+        let top = bottom + view.wall_height * scale / depth;
+        let v = (top - screen.y) / (top - bottom);
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            continue;
+        }
+        add_surface(depth, vec4<f32>(color.rgb, 1.0) * sample(u32(sprite.z), u, v));
+    }
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Snap to the middle of the column the pixel is in, as the CPU path does
+    let column_width = 2.0 / view.columns;
+    let column = min(floor((in.screen.x + 1.0) / column_width), view.columns - 1.0);
+    let screen_x = -1.0 + column_width * (column + 0.5);
+    let ray = view.direction + view.plane * screen_x;
+
+    nearest = Layer(1e30, vec4<f32>(0.0));
+    layer_count = 0u;
+    // Height above the horizon, which looking up and down moves
+    let y = in.screen.y - view.horizon;
+    // Every storey is drawn, each from the eye's height above its floor
+    for (var i = 0u; i < view.storey_count; i++) {
+        let storey = storey_at(i);
+        let eye = view.eye - storey.offset;
+        let stop = cast_ray(storey, ray, y, eye);
+        add_sprites(storey, in.screen, stop, eye);
+    }
+    return composite(y);
+}
@@ -1,11 +1,15 @@
 struct VertexInput {
     @location(0) position: vec2<f32>,
     @location(1) color: vec3<f32>,
+    @location(2) tex_coords: vec2<f32>,
+    @location(3) depth: f32,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) depth: f32,
 };
 
 @vertex
@@ -14,11 +18,48 @@ fn vs_main(
 ) -> VertexOutput {
     var out: VertexOutput;
     out.color = vec4<f32>(model.color, 1.0);
-    out.clip_position = vec4<f32>(model.position, 0.0, 1.0);
+    out.tex_coords = model.tex_coords;
+    out.depth = model.depth;
+    // Scaling by depth leaves the on screen position unchanged but makes the
+    // rasterizer interpolate texture coordinates with perspective
+    let w = select(1.0, model.depth, model.depth > 0.0);
+    out.clip_position = vec4<f32>(model.position * w, 0.0, w);
     return out;
 }
 
+@group(0) @binding(0)
+var t_atlas: texture_2d<f32>;
+@group(0) @binding(1)
+var s_atlas: sampler;
+
+struct Fog {
+    color: vec3<f32>,
+    density: f32,
+    mode: u32,
+};
+
+@group(1) @binding(0)
+var<uniform> fog: Fog;
+
+fn fog_amount(depth: f32) -> f32 {
+    // Geometry outside the 3D view has no depth and is never fogged
+    if depth <= 0.0 || fog.mode == 0u {
+        return 0.0;
+    }
+    if fog.mode == 1u {
+        return clamp(depth * fog.density, 0.0, 1.0);
+    }
+    return 1.0 - exp(-depth * fog.density);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(in.color);
+    let texel = textureSample(t_atlas, s_atlas, in.tex_coords);
+    // Clear texels, such as around a sprite, let whatever is behind show
+    // through. Anything partly transparent is blended over what is behind it.
+    if texel.a < 0.01 {
+        discard;
+    }
+    let color = in.color * texel;
+    return vec4<f32>(mix(color.rgb, fog.color, fog_amount(in.depth)), color.a);
 }